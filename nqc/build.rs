@@ -12,6 +12,18 @@ mod filters {
     pub fn hex(n: &u8) -> askama::Result<String> {
         Ok(format!("0x{n:02x}"))
     }
+
+    /// `SetMotorPower` -> `set_motor_power`, the assembler mnemonic
+    pub fn snake_case(name: &str) -> askama::Result<String> {
+        let mut out = String::new();
+        for (idx, ch) in name.chars().enumerate() {
+            if ch.is_ascii_uppercase() && idx > 0 {
+                out.push('_');
+            }
+            out.push(ch.to_ascii_lowercase());
+        }
+        Ok(out)
+    }
 }
 
 const fn true_() -> bool {
@@ -25,8 +37,6 @@ struct Opcode {
     description: String,
     #[serde(default)]
     context: Context,
-    #[serde(default = "true_")]
-    supports_alternate: bool,
     request: RequestResponse,
     response: Option<RequestResponse>,
 }
@@ -55,6 +65,8 @@ impl Default for Context {
 #[derive(Deserialize)]
 struct RequestResponse {
     opcode: u8,
+    #[serde(default = "true_")]
+    supports_alternate: bool,
     #[serde(default)]
     params: Vec<Param>,
 }
@@ -63,6 +75,11 @@ fn u8_() -> String {
     "u8".into()
 }
 
+/// Types of parameters which are encoded as they are. Any other type
+/// names a type in `enums.rs` with checked conversions to and from a
+/// byte.
+const PRIMITIVE_TYPES: &[&str] = &["u8", "i8", "u16", "i16", "Vec<u8>"];
+
 #[derive(Deserialize)]
 struct Param {
     name: String,
@@ -70,6 +87,16 @@ struct Param {
     ty: String,
 }
 
+impl Param {
+    fn qualify_type(&mut self) {
+        if !PRIMITIVE_TYPES.contains(&self.ty.as_str())
+            && !self.ty.starts_with('[')
+        {
+            self.ty = format!("enums::{}", self.ty);
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "opcodes.rs", escape = "none")]
 struct OpcodesTemplate {
@@ -85,7 +112,16 @@ fn main() {
     let opcodes_file = Path::new(env!("CARGO_MANIFEST_DIR")).join(OPCODES_FILE);
     let file = std::fs::File::open(opcodes_file).unwrap();
     let reader = BufReader::new(file);
-    let opcodes: Vec<Opcode> = serde_yaml::from_reader(reader).unwrap();
+    let mut opcodes: Vec<Opcode> = serde_yaml::from_reader(reader).unwrap();
+    for opcode in &mut opcodes {
+        let responses = opcode.response.iter_mut();
+        for params in std::iter::once(&mut opcode.request)
+            .chain(responses)
+            .map(|params| &mut params.params)
+        {
+            params.iter_mut().for_each(Param::qualify_type);
+        }
+    }
     let templ = OpcodesTemplate { opcodes };
 
     let codegen = templ.render().unwrap();
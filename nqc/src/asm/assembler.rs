@@ -0,0 +1,546 @@
+use crate::{
+    asm::{
+        ast::{Line, Operand, Statement},
+        parser,
+    },
+    binfmt::{RcxBin, Section, SectionType, Symbol, SymbolType, TargetType},
+    opcodes::{self, Opcode},
+    Error, Result,
+};
+use std::{collections::HashMap, ffi::CString};
+
+const RCX_TAG: [u8; 4] = *b"RCXI";
+const RCX_VERSION: u16 = 0x0102;
+
+/// Largest encoded instruction
+const MAX_INSTRUCTION_LEN: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BranchKind {
+    /// `branch_always_{near,far}`
+    Always,
+    /// `decrement_loop_counter_{near,far}`
+    LoopCounter,
+    /// `test_and_branch_{near,far}`, after its four comparison operands
+    Test,
+}
+
+impl BranchKind {
+    fn mnemonic(self, far: bool) -> &'static str {
+        match (self, far) {
+            (Self::Always, false) => "branch_always_near",
+            (Self::Always, true) => "branch_always_far",
+            (Self::LoopCounter, false) => "decrement_loop_counter_near",
+            (Self::LoopCounter, true) => "decrement_loop_counter_far",
+            (Self::Test, false) => "test_and_branch_near",
+            (Self::Test, true) => "test_and_branch_far",
+        }
+    }
+
+    /// Distance from the start of the instruction to its offset, which
+    /// the branch target is relative to
+    fn offset_position(self) -> usize {
+        match self {
+            Self::Always | Self::LoopCounter => 1,
+            Self::Test => 6,
+        }
+    }
+
+    fn len(self, far: bool) -> usize {
+        self.offset_position() + if far { 2 } else { 1 }
+    }
+
+    /// Operands encoding a branch `distance` bytes from the offset, or
+    /// `None` if the encoding cannot reach that far
+    fn offset_args(self, far: bool, distance: i32) -> Option<Vec<i32>> {
+        Some(match (self, far) {
+            (Self::Always, false) => match distance {
+                0..=0x7f => vec![distance],
+                -0x7f..=-1 => vec![0x80 - distance],
+                _ => None?,
+            },
+            (Self::Always, true) => match distance {
+                0..=0x7fff => vec![distance & 0x7f, distance >> 7],
+                -0x7f7f..=-1 => {
+                    let back = 0x80 - distance;
+                    vec![0x80 | (back & 0x7f), (back >> 7) - 1]
+                }
+                _ => None?,
+            },
+            (Self::LoopCounter | Self::Test, false) => match distance {
+                0..=0xff => vec![distance],
+                _ => None?,
+            },
+            (Self::LoopCounter, true) => match distance {
+                0..=0xffff => vec![distance],
+                _ => None?,
+            },
+            (Self::Test, true) => match distance {
+                -0x8000..=0x7fff => vec![distance],
+                _ => None?,
+            },
+        })
+    }
+}
+
+/// A branch whose encoding depends on how far away its label is
+#[derive(Debug)]
+struct Branch<'input> {
+    line: usize,
+    kind: BranchKind,
+    /// Operands preceding the offset
+    args: Vec<i32>,
+    label: &'input str,
+    far: bool,
+    /// Whether the near or far form was named explicitly
+    fixed: bool,
+}
+
+impl Branch<'_> {
+    fn len(&self) -> usize {
+        self.kind.len(self.far)
+    }
+
+    /// Operands for the branch at `start` to `target`, if it can reach
+    fn args(&self, start: usize, target: usize) -> Option<Vec<i32>> {
+        let offset = start + self.kind.offset_position();
+        let distance =
+            i32::try_from(target).ok()? - i32::try_from(offset).ok()?;
+        let mut args = self.args.clone();
+        args.extend(self.kind.offset_args(self.far, distance)?);
+        Some(args)
+    }
+}
+
+#[derive(Debug)]
+enum Item<'input> {
+    Code(Vec<u8>),
+    Branch(Branch<'input>),
+}
+
+impl Item<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Code(code) => code.len(),
+            Self::Branch(branch) => branch.len(),
+        }
+    }
+}
+
+/// A task or subroutine being assembled
+struct SectionBuilder<'input> {
+    ty: SectionType,
+    number: u8,
+    items: Vec<Item<'input>>,
+    /// Index into `items` of the instruction following each label
+    labels: HashMap<&'input str, usize>,
+}
+
+impl SectionBuilder<'_> {
+    /// Address of each item, followed by the end of the section
+    fn layout(&self) -> Vec<usize> {
+        let mut addr = 0;
+        let mut out = vec![0];
+        out.extend(self.items.iter().map(|item| {
+            addr += item.len();
+            addr
+        }));
+        out
+    }
+
+    /// Widen near branches which cannot reach their labels until every
+    /// branch fits. Branches only ever grow, so this terminates.
+    fn relax(&mut self) -> Result<()> {
+        loop {
+            let layout = self.layout();
+            let mut changed = false;
+            for (idx, item) in self.items.iter_mut().enumerate() {
+                let Item::Branch(branch) = item else {
+                    continue;
+                };
+                let target = layout[self.labels[branch.label]];
+                if branch.args(layout[idx], target).is_some() {
+                    continue;
+                }
+                if branch.far || branch.fixed {
+                    return Err(asm_error(
+                        branch.line,
+                        Error::Parse("Branch target out of range"),
+                    ));
+                }
+                branch.far = true;
+                changed = true;
+            }
+            if !changed {
+                return Ok(());
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<Section> {
+        for item in &self.items {
+            if let Item::Branch(branch) = item {
+                if !self.labels.contains_key(branch.label) {
+                    return Err(asm_error(
+                        branch.line,
+                        Error::Parse("Unknown label"),
+                    ));
+                }
+            }
+        }
+        self.relax()?;
+
+        let layout = self.layout();
+        let mut data = Vec::new();
+        for (idx, item) in self.items.iter().enumerate() {
+            match item {
+                Item::Code(code) => data.extend_from_slice(code),
+                Item::Branch(branch) => {
+                    // relax has checked that every branch fits
+                    let target = layout[self.labels[branch.label]];
+                    let code = branch
+                        .args(layout[idx], target)
+                        .ok_or(Error::Parse("Branch target out of range"))
+                        .and_then(|args| {
+                            encode(&opcodes::assemble_opcode(
+                                branch.kind.mnemonic(branch.far),
+                                &args,
+                            )?)
+                        })
+                        .map_err(|err| asm_error(branch.line, err))?;
+                    data.extend(code);
+                }
+            }
+        }
+
+        Ok(Section {
+            ty: self.ty,
+            number: self.number,
+            length: u16::try_from(data.len())?,
+            data,
+        })
+    }
+}
+
+fn asm_error(line: usize, source: Error) -> Error {
+    Error::Asm {
+        line,
+        source: Box::new(source),
+    }
+}
+
+/// Bytecode for an instruction: its opcode followed by its parameters
+fn encode(opcode: &impl Opcode) -> Result<Vec<u8>> {
+    let mut buf = [0; MAX_INSTRUCTION_LEN];
+    buf[0] = opcode.request_opcode();
+    let len = opcode.serialise(&mut buf[1..])?;
+    Ok(buf[..=len].to_vec())
+}
+
+/// Names given by the `.task`, `.sub` and `.var` directives
+#[derive(Default)]
+struct SymbolTable<'input> {
+    names: HashMap<&'input str, u8>,
+    symbols: Vec<Symbol>,
+}
+
+impl<'input> SymbolTable<'input> {
+    fn add(
+        &mut self,
+        ty: SymbolType,
+        index: u8,
+        name: &'input str,
+    ) -> Result<()> {
+        if self.names.insert(name, index).is_some() {
+            return Err(Error::Parse("Duplicate name"));
+        }
+        if self
+            .symbols
+            .iter()
+            .any(|sym| sym.ty == ty && sym.index == index)
+        {
+            return Err(Error::Parse("Duplicate number"));
+        }
+        let name = CString::new(name)
+            .map_err(|_| Error::Parse("Name contains a nul byte"))?;
+        self.symbols.push(Symbol {
+            ty,
+            index,
+            length: u16::try_from(name.as_bytes_with_nul().len())?,
+            name,
+        });
+        Ok(())
+    }
+
+    fn resolve(&self, operand: &Operand) -> Result<i32> {
+        match operand {
+            Operand::Number(number) => Ok(*number),
+            Operand::Name(name) => self
+                .names
+                .get(name)
+                .map(|&index| index.into())
+                .ok_or(Error::Parse("Unknown name")),
+        }
+    }
+}
+
+/// Parse a label-taking branch, given with or without its `_near` or
+/// `_far` suffix, or `None` for any other mnemonic
+fn parse_branch<'input>(
+    line: usize,
+    mnemonic: &str,
+    args: &[Operand<'input>],
+    symbols: &SymbolTable,
+) -> Option<Result<Branch<'input>>> {
+    let (base, far, fixed) = if let Some(base) = mnemonic.strip_suffix("_near")
+    {
+        (base, false, true)
+    } else if let Some(base) = mnemonic.strip_suffix("_far") {
+        (base, true, true)
+    } else {
+        (mnemonic, false, false)
+    };
+    let kind = match base {
+        "jmp" if !fixed => BranchKind::Always,
+        "branch_always" => BranchKind::Always,
+        "decrement_loop_counter" => BranchKind::LoopCounter,
+        "test_and_branch" => BranchKind::Test,
+        _ => return None,
+    };
+    // with a number as its offset, an explicit form is assembled as is
+    let (Operand::Name(label), args) = args.split_last()? else {
+        return None;
+    };
+
+    Some(
+        args.iter()
+            .map(|arg| symbols.resolve(arg))
+            .collect::<Result<_>>()
+            .map(|args| Branch {
+                line,
+                kind,
+                args,
+                label,
+                far,
+                fixed,
+            }),
+    )
+}
+
+/// Assemble RCX bytecode into a program for `target`.
+///
+/// Each line holds a label (`name:`), a directive or an instruction, and
+/// may end in a `;` comment. `.task N name` and `.sub N name` begin task
+/// or subroutine `N`, and `.var N name` names variable `N`; the names are
+/// written to the symbol table and may be used as operands.
+///
+/// Instructions are the snake_case names of the opcodes, followed by a
+/// number for each value of their parameters. `jmp`, `branch_always`,
+/// `decrement_loop_counter` and `test_and_branch` take a label in place
+/// of their offset and use the near encoding where it reaches, or the far
+/// encoding otherwise; the `_near` and `_far` forms may also take a label
+/// to require that encoding.
+///
+/// ```
+/// # use nqc::{asm, binfmt::TargetType};
+/// let bin = asm::assemble(
+///     ".task 0 main
+///     loop:
+///         play_sound 1
+///         jmp loop",
+///     TargetType::Rcx,
+/// )
+/// .unwrap();
+/// assert_eq!(bin.sections[0].data, [0x51, 0x01, 0x27, 0x83]);
+/// ```
+pub fn assemble(input: &str, target: TargetType) -> Result<RcxBin> {
+    let lines = parser::parse(input)?;
+
+    // collect names first, so they can be used before their directive
+    let mut symbols = SymbolTable::default();
+    for line in &lines {
+        if let Statement::Directive { ty, number, name } = line.statement {
+            symbols
+                .add(ty, number, name)
+                .map_err(|err| asm_error(line.number, err))?;
+        }
+    }
+
+    let mut sections = Vec::new();
+    let mut current: Option<SectionBuilder> = None;
+    for Line { number, statement } in &lines {
+        match statement {
+            Statement::Directive { ty, number, .. } => {
+                let ty = match ty {
+                    SymbolType::Task => SectionType::Task,
+                    SymbolType::Sub => SectionType::Subroutine,
+                    SymbolType::Var => continue,
+                };
+                if let Some(section) = current.take() {
+                    sections.push(section.finish()?);
+                }
+                current = Some(SectionBuilder {
+                    ty,
+                    number: *number,
+                    items: Vec::new(),
+                    labels: HashMap::new(),
+                });
+            }
+            Statement::Label(label) => {
+                let section = current.as_mut().ok_or_else(|| {
+                    asm_error(*number, Error::Parse("Label outside a section"))
+                })?;
+                if section.labels.insert(label, section.items.len()).is_some() {
+                    return Err(asm_error(
+                        *number,
+                        Error::Parse("Duplicate label"),
+                    ));
+                }
+            }
+            Statement::Instruction { mnemonic, args } => {
+                let section = current.as_mut().ok_or_else(|| {
+                    asm_error(
+                        *number,
+                        Error::Parse("Instruction outside a section"),
+                    )
+                })?;
+                let item =
+                    match parse_branch(*number, mnemonic, args, &symbols) {
+                        Some(branch) => branch.map(Item::Branch),
+                        None => args
+                            .iter()
+                            .map(|arg| symbols.resolve(arg))
+                            .collect::<Result<Vec<_>>>()
+                            .and_then(|args| {
+                                opcodes::assemble_opcode(mnemonic, &args)
+                            })
+                            .and_then(|opcode| encode(&opcode))
+                            .map(Item::Code),
+                    }
+                    .map_err(|err| asm_error(*number, err))?;
+                section.items.push(item);
+            }
+        }
+    }
+    if let Some(section) = current {
+        sections.push(section.finish()?);
+    }
+
+    let bin = RcxBin {
+        signature: RCX_TAG,
+        version: RCX_VERSION,
+        section_count: u16::try_from(sections.len())?,
+        symbol_count: u16::try_from(symbols.symbols.len())?,
+        target_type: target,
+        reserved: 0,
+        sections,
+        symbols: symbols.symbols,
+    };
+    bin.verify()?;
+    Ok(bin)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::binfmt::test::COMPLEX;
+    use pretty_assertions::assert_eq;
+
+    /// Source for the `COMPLEX` fixture in `binfmt`
+    const COMPLEX_SRC: &str = "
+.sub 0 set_fwd
+    set_motor_direction 0x81
+    set_motor_on_off 0x81
+
+.task 0 main
+    set_motor_power 7 2 7
+    set_motor_direction 0x87
+    set_motor_power 1 2 50
+    call_subroutine set_fwd
+    start_task loop_task
+
+.task 1 loop_task
+    set_variable power 2 50
+    set_variable delta 2 5
+loop:
+    set_motor_power 1 0 power
+    add_to_variable power 0 delta
+    test_and_branch 0x42 0 89 0 too_fast
+    set_variable delta 2 -2
+    jmp wait
+too_fast:
+    test_and_branch 2 0 11 0 wait
+    set_variable delta 2 2
+wait:
+    wait 2 100
+    jmp loop
+
+.var 0 power
+.var 1 delta
+";
+
+    fn assemble_task(body: &str) -> Result<Vec<u8>> {
+        let bin = assemble(&format!(".task 0 main\n{body}"), TargetType::Rcx)?;
+        Ok(bin.sections[0].data.clone())
+    }
+
+    #[test]
+    fn assemble_complex() {
+        let bin = assemble(COMPLEX_SRC, TargetType::Rcx).unwrap();
+        assert_eq!(bin, RcxBin::parse(COMPLEX).unwrap());
+        assert_eq!(bin.to_bytes().unwrap(), COMPLEX);
+    }
+
+    #[test]
+    fn far_branches() {
+        let padding = "set_variable 0 2 0\n".repeat(100);
+
+        let data =
+            assemble_task(&format!("jmp end\n{padding}end:\nstop_all_tasks"))
+                .unwrap();
+        assert_eq!(data[..3], [0x72, 0x76, 0x03]);
+
+        let data = assemble_task(&format!("top:\n{padding}jmp top")).unwrap();
+        assert_eq!(data[500..], [0x72, 0xf5, 0x03]);
+
+        let data = assemble_task(&format!(
+            "decrement_loop_counter end\n{padding}end:"
+        ))
+        .unwrap();
+        assert_eq!(data[..3], [0x92, 0xf6, 0x01]);
+
+        // explicit near branches are not widened, and loop counter
+        // branches cannot go backwards
+        assert!(assemble_task(&format!(
+            "branch_always_near end\n{padding}end:"
+        ))
+        .is_err());
+        assert!(assemble_task("top:\ndecrement_loop_counter top").is_err());
+
+        // explicit forms still take a raw offset
+        assert_eq!(
+            assemble_task("branch_always_far 1 2").unwrap(),
+            [0x72, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for (src, line) in [
+            (".task 0 main\nfly_away", 2),
+            (".task 0 main\nplay_sound 9", 2),
+            (".task 0 main\nstart_task 1 2", 2),
+            (".task 0 main\n\njmp nowhere", 3),
+            (".task 0 main\ntop:\ntop:", 3),
+            ("alive", 1),
+            (".task 0 main\n.var 0 main", 2),
+            (".task 0 main\n.task 0 other", 2),
+        ] {
+            let err = assemble(src, TargetType::Rcx).unwrap_err();
+            assert!(
+                matches!(err, Error::Asm { line: l, .. } if l == line),
+                "{src}"
+            );
+        }
+    }
+}
@@ -1,6 +1,30 @@
+use crate::binfmt::SymbolType;
+
+/// A non-empty line of assembly, with its 1-based line number
 #[derive(Debug, PartialEq, Eq)]
-pub enum LabelOrOpcode<'input> {
+pub struct Line<'input> {
+    pub number: usize,
+    pub statement: Statement<'input>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Statement<'input> {
     Label(&'input str),
-    Jmp(&'input str),
-    Opcode(&'input str, Vec<u8>),
+    /// `.task N name`, `.sub N name` or `.var N name`
+    Directive {
+        ty: SymbolType,
+        number: u8,
+        name: &'input str,
+    },
+    Instruction {
+        mnemonic: &'input str,
+        args: Vec<Operand<'input>>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Operand<'input> {
+    Number(i32),
+    /// A label, or the name given to a task, subroutine or variable
+    Name(&'input str),
 }
@@ -1,9 +1,15 @@
+//! Assembler for RCX bytecode
+//!
+//! 1. the parser splits source into labels, directives and instructions
+//! 2. mnemonics are resolved to the generated opcodes, which validate
+//!    their operands
+//! 3. branches are sized to reach their labels and encoded
+//! 4. sections and symbols are collected into an [`RcxBin`]
+//!
+//! [`RcxBin`]: crate::binfmt::RcxBin
+
+mod assembler;
 pub mod ast;
 pub mod parser;
 
-// 1. lexer to take source asm file into Vec<LabelOrOpcode>
-//    - parse hex and decimal numbers
-// 2. parser to convert string opcode names into proper opcode types
-//    and validate they they have the correct arguments
-// 3. assembler to convert into bytecode and resolve labels & jumps
-// 4. something to write out the binary file
+pub use assembler::assemble;
@@ -1,82 +1,164 @@
-use crate::asm::ast::LabelOrOpcode;
+use crate::{
+    asm::ast::{Line, Operand, Statement},
+    binfmt::SymbolType,
+    Error, Result,
+};
 use regex::Regex;
 use std::sync::LazyLock;
 
 static LABEL_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*:$").unwrap());
-static JMP_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r"^\s*jmp\s+[a-zA-Z_][a-zA-Z0-9_]*$").unwrap());
-static OPCODE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(r"^\s*[a-zA-Z_][a-zA-Z0-9_]*(\s+((0x[0-9a-fA-F]+)|([0-9]+)))*$")
-        .unwrap()
-});
+    LazyLock::new(|| Regex::new("^([a-zA-Z_][a-zA-Z0-9_]*):$").unwrap());
+static NAME_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap());
 
-fn parse_number(inp: &str) -> u8 {
-    if let Some(inp) = inp.strip_prefix("0x") {
-        u8::from_str_radix(inp, 16).unwrap()
+/// Everything after this is a comment
+const COMMENT: char = ';';
+
+fn parse_number(inp: &str) -> Result<i32> {
+    let (negative, inp) = match inp.strip_prefix('-') {
+        Some(inp) => (true, inp),
+        None => (false, inp),
+    };
+    let value = if let Some(inp) = inp.strip_prefix("0x") {
+        i32::from_str_radix(inp, 16)
     } else {
-        inp.parse().unwrap()
+        inp.parse()
     }
+    .map_err(|_| Error::Parse("Invalid number"))?;
+    Ok(if negative { -value } else { value })
 }
 
-pub fn parse(input: &str) -> Vec<LabelOrOpcode> {
-    let mut out = Vec::new();
-    let mut errors = Vec::new();
+fn parse_name(inp: &str) -> Result<&str> {
+    if NAME_REGEX.is_match(inp) {
+        Ok(inp)
+    } else {
+        Err(Error::Parse("Invalid name"))
+    }
+}
 
-    for (idx, line) in input.lines().enumerate() {
-        if LABEL_REGEX.is_match(line) {
-            out.push(LabelOrOpcode::Label(line.strip_suffix(':').unwrap()));
-        } else if JMP_REGEX.is_match(line) {
-            out.push(LabelOrOpcode::Jmp(
-                line.split_whitespace().nth(1).unwrap(),
-            ));
-        } else if OPCODE_REGEX.is_match(line) {
-            let mut iter = line.split_whitespace();
-            let opcode = iter.next().unwrap();
-            let args = iter.map(parse_number).collect();
-            out.push(LabelOrOpcode::Opcode(opcode, args));
-        } else {
-            errors.push((idx + 1, "Invalid line"));
-        }
+fn parse_operand(inp: &str) -> Result<Operand<'_>> {
+    if NAME_REGEX.is_match(inp) {
+        Ok(Operand::Name(inp))
+    } else {
+        parse_number(inp).map(Operand::Number)
+    }
+}
+
+fn parse_directive<'input>(
+    directive: &str,
+    mut args: impl Iterator<Item = &'input str>,
+) -> Result<Statement<'input>> {
+    let ty = match directive {
+        "task" => SymbolType::Task,
+        "sub" => SymbolType::Sub,
+        "var" => SymbolType::Var,
+        _ => return Err(Error::Parse("Unknown directive")),
+    };
+    let number = args.next().ok_or(Error::Parse("Missing number"))?;
+    let number = u8::try_from(parse_number(number)?)?;
+    let name = parse_name(args.next().ok_or(Error::Parse("Missing name"))?)?;
+    if args.next().is_some() {
+        return Err(Error::Parse("Too many arguments"));
+    }
+    Ok(Statement::Directive { ty, number, name })
+}
+
+/// Parse a line with its comment and surrounding whitespace removed
+fn parse_line(line: &str) -> Result<Statement<'_>> {
+    if let Some(caps) = LABEL_REGEX.captures(line) {
+        // the regex has exactly one group, so it is always present
+        return Ok(Statement::Label(caps.get(1).unwrap().as_str()));
     }
 
-    if !errors.is_empty() {
-        panic!("{errors:?}");
+    let mut tokens = line.split_whitespace();
+    let first = tokens.next().ok_or(Error::Parse("Empty line"))?;
+    if let Some(directive) = first.strip_prefix('.') {
+        return parse_directive(directive, tokens);
     }
 
-    out
+    Ok(Statement::Instruction {
+        mnemonic: parse_name(first)?,
+        args: tokens.map(parse_operand).collect::<Result<_>>()?,
+    })
+}
+
+/// Split assembly source into its statements, skipping blank lines and
+/// comments
+pub fn parse(input: &str) -> Result<Vec<Line<'_>>> {
+    input
+        .lines()
+        .enumerate()
+        .filter_map(|(idx, line)| {
+            let line = line.split(COMMENT).next().unwrap_or_default().trim();
+            (!line.is_empty()).then_some((idx + 1, line))
+        })
+        .map(|(number, line)| {
+            let statement = parse_line(line).map_err(|source| Error::Asm {
+                line: number,
+                source: Box::new(source),
+            })?;
+            Ok(Line { number, statement })
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::asm::ast::*;
+
+    fn parse_one(line: &str) -> Statement<'_> {
+        parse(line).unwrap().remove(0).statement
+    }
 
     #[test]
-    fn label_or_opcode() {
+    fn statements() {
         for (case, expected) in [
-            ("label:", vec![LabelOrOpcode::Label("label")]),
-            ("opcode 5", vec![LabelOrOpcode::Opcode("opcode", vec![5])]),
+            ("label:", Statement::Label("label")),
+            (
+                "opcode 5",
+                Statement::Instruction {
+                    mnemonic: "opcode",
+                    args: vec![Operand::Number(5)],
+                },
+            ),
             (
-                "opcode 5 6 0x7",
-                vec![LabelOrOpcode::Opcode("opcode", vec![5, 6, 7])],
+                "  opcode 5 -6 0x7 ; comment",
+                Statement::Instruction {
+                    mnemonic: "opcode",
+                    args: vec![
+                        Operand::Number(5),
+                        Operand::Number(-6),
+                        Operand::Number(7),
+                    ],
+                },
             ),
             (
-                "opcode    0xff",
-                vec![LabelOrOpcode::Opcode("opcode", vec![0xff])],
+                "jmp GAME",
+                Statement::Instruction {
+                    mnemonic: "jmp",
+                    args: vec![Operand::Name("GAME")],
+                },
+            ),
+            (
+                ".var 0x1f power",
+                Statement::Directive {
+                    ty: SymbolType::Var,
+                    number: 31,
+                    name: "power",
+                },
             ),
-            ("jmp GAME", vec![LabelOrOpcode::Jmp("GAME")]),
         ] {
-            dbg!(case);
-            assert_eq!(parse(case), expected,);
+            assert_eq!(parse_one(case), expected, "{case}");
         }
     }
 
-    // #[test]
-    // fn opcode() {
-    //     assert_eq!(
-    //         asm::OpcodeParser::new().parse("game 3").unwrap(),
-    //         LabelOrOpcode::Opcode("game", vec![5])
-    //     );
-    // }
+    #[test]
+    fn errors() {
+        let lines = parse("; header\n\nalive\n  stop_task 0x\n").unwrap_err();
+        assert!(matches!(lines, Error::Asm { line: 4, .. }));
+
+        for case in [".task main", ".func 0 main", ".var 256 x", "3dots:"] {
+            assert!(parse(case).is_err(), "{case}");
+        }
+    }
 }
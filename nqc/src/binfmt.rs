@@ -17,7 +17,7 @@
 //!  * for each symbol:
 //!   - type - 1 byte
 //!   - index - 1 byte
-//!   - length - 1 byte
+//!   - length - 2 bytes
 //!   - name - <length> bytes cstr
 //! ```
 
@@ -26,6 +26,7 @@ use nom::{number::Endianness, IResult};
 use std::{
     ffi::CString,
     fmt::{self, Debug, Display, Formatter, Write},
+    io,
 };
 use tracing::trace;
 
@@ -34,6 +35,11 @@ const MAX_SECTIONS: usize = 10;
 const INDENT: &str = "  ";
 const HEXDUMP_WRAP_BYTES: usize = 16;
 
+/// Number of bytes padding a section of `length` to u32 alignment
+fn padding(length: u16) -> usize {
+    usize::from((4 - (length % 4)) & 3)
+}
+
 fn print_hex_with_marker_at(bin: &[u8], pos: usize) -> String {
     let mut out = String::new();
 
@@ -56,7 +62,7 @@ fn print_hex_with_marker_at(bin: &[u8], pos: usize) -> String {
         {
             // indent the marker appropriately
             out += "     "; // indent past the offset display
-            out.extend(std::iter::repeat("   ").take(pos % HEXDUMP_WRAP_BYTES));
+            out.extend(std::iter::repeat_n("   ", pos % HEXDUMP_WRAP_BYTES));
             writeln!(&mut out, "^<<").unwrap();
         }
     }
@@ -95,6 +101,39 @@ impl RcxBin {
         Ok(bin)
     }
 
+    /// Write the image in the `.rcx` format. The section and symbol
+    /// counts and lengths are taken from the data rather than from the
+    /// stored fields, so they need not be kept up to date.
+    pub fn write(&self, mut out: impl io::Write) -> Result<()> {
+        out.write_all(&self.signature)?;
+        out.write_all(&self.version.to_le_bytes())?;
+        out.write_all(&u16::try_from(self.sections.len())?.to_le_bytes())?;
+        out.write_all(&u16::try_from(self.symbols.len())?.to_le_bytes())?;
+        out.write_all(&[self.target_type as u8, self.reserved])?;
+
+        for section in &self.sections {
+            let length = u16::try_from(section.data.len())?;
+            out.write_all(&[section.ty as u8, section.number])?;
+            out.write_all(&length.to_le_bytes())?;
+            out.write_all(&section.data)?;
+            out.write_all(&[0; 3][..padding(length)])?;
+        }
+
+        for symbol in &self.symbols {
+            let name = symbol.name.as_bytes_with_nul();
+            out.write_all(&[symbol.ty as u8, symbol.index])?;
+            out.write_all(&u16::try_from(name.len())?.to_le_bytes())?;
+            out.write_all(name)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.write(&mut out)?;
+        Ok(out)
+    }
+
     pub fn verify(&self) -> Result<()> {
         fn repeated_idx(sections: &[Section]) -> bool {
             let mut c = sections
@@ -247,7 +286,7 @@ fn parse_section(i: &[u8]) -> IResult<&[u8], Section> {
     trace!("  data: {data:02x?}");
 
     // read padding bytes
-    let (i, _pad) = nom::bytes::complete::take((4 - (length % 4)) & 3)(i)?;
+    let (i, _pad) = nom::bytes::complete::take(padding(length))(i)?;
 
     Ok((
         i,
@@ -383,7 +422,7 @@ impl Display for SymbolType {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
     use hex_literal::hex;
 
@@ -393,7 +432,9 @@ mod test {
         430264002141000005006d61696e00"
     );
 
-    const COMPLEX: &[u8] = &hex!(
+    /// Two tasks, a subroutine and two variables, assembled from the
+    /// source in the assembler tests
+    pub(crate) const COMPLEX: &[u8] = &hex!(
         "52435849020103000500000001000400e181218100000e0013070207e187
     130102321700710100000001330014000232001401020500130100002400
     00010085420059000008140102feff270d8502000b000006140102020043
@@ -437,6 +478,32 @@ mod test {
         assert_eq!(out, expected);
     }
 
+    #[test]
+    fn write_round_trip() {
+        for fixture in [SAMPLE, COMPLEX] {
+            let bin = RcxBin::parse(fixture).unwrap();
+            assert_eq!(bin.to_bytes().unwrap(), fixture);
+        }
+    }
+
+    #[test]
+    fn write_recomputes_lengths() {
+        let bin = RcxBin::parse(COMPLEX).unwrap();
+        let mut stale = bin.clone();
+        stale.section_count = 0;
+        stale.symbol_count = 9;
+        stale.sections[0].length = 1;
+        stale.symbols[0].length = 0;
+        assert_eq!(RcxBin::parse(&stale.to_bytes().unwrap()).unwrap(), bin);
+
+        let mut grown = bin.clone();
+        grown.sections[0].data.push(0x10);
+        let reparsed = RcxBin::parse(&grown.to_bytes().unwrap()).unwrap();
+        assert_eq!(reparsed.sections[0].length, 5);
+        assert_eq!(reparsed.sections[0].data, grown.sections[0].data);
+        assert_eq!(reparsed.sections[1..], bin.sections[1..]);
+    }
+
     #[test]
     fn parse_sample() {
         let bin = RcxBin::parse(SAMPLE).unwrap();
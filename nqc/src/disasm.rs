@@ -168,41 +168,53 @@ impl Display for BranchType {
     }
 }
 
-/// If the opcode is a branch then returns its offset
+/// If the opcode is a branch then returns its offset. `pc` is the
+/// address following the branch instruction, and targets are relative to
+/// the address of its offset parameter.
 fn is_branch(opcode: &Opcodes, pc: usize) -> Option<BranchType> {
     Some(match opcode {
         Opcodes::BranchAlwaysFar(opcode) => {
-            // pc has already been advanced to the address of extension,
-            // so the offset is one byte earlier
-            let address_of_offset = pc - 1;
+            // offset is followed by the extension byte
+            let address_of_offset = pc - 2;
             let target = if opcode.offset & 0x80 == 0 {
                 address_of_offset
                     + usize::from(opcode.offset)
                     + 128 * usize::from(opcode.extension)
             } else {
-                address_of_offset + 128
-                    - usize::from(opcode.offset)
-                    - 128 * usize::from(opcode.extension)
+                (address_of_offset + 128).checked_sub(
+                    usize::from(opcode.offset)
+                        + 128 * usize::from(opcode.extension),
+                )?
             };
             BranchType::Unconditional(target)
         }
         Opcodes::BranchAlwaysNear(opcode) => {
-            let address_of_offset = pc;
+            let address_of_offset = pc - 1;
             let target = if opcode.offset & 0x80 == 0 {
                 address_of_offset + usize::from(opcode.offset)
             } else {
-                address_of_offset + 128 - usize::from(opcode.offset)
+                (address_of_offset + 128)
+                    .checked_sub(usize::from(opcode.offset))?
             };
             BranchType::Unconditional(target)
         }
-        Opcodes::DecrementLoopCounterFar(_opcode) => {
-            todo!()
+        Opcodes::DecrementLoopCounterFar(opcode) => {
+            let address_of_offset = pc - 2;
+            BranchType::Conditional(
+                address_of_offset + usize::from(opcode.offset),
+            )
         }
-        Opcodes::DecrementLoopCounterNear(_opcode) => {
-            todo!()
+        Opcodes::DecrementLoopCounterNear(opcode) => {
+            let address_of_offset = pc - 1;
+            BranchType::Conditional(
+                address_of_offset + usize::from(opcode.offset),
+            )
         }
-        Opcodes::TestAndBranchFar(_opcode) => {
-            todo!()
+        Opcodes::TestAndBranchFar(opcode) => {
+            let address_of_offset = pc - 2;
+            let target =
+                address_of_offset.checked_add_signed(opcode.offset.into())?;
+            BranchType::Conditional(target)
         }
         Opcodes::TestAndBranchNear(opcode) => {
             let address_of_offset = pc - 1;
@@ -216,6 +228,8 @@ fn is_branch(opcode: &Opcodes, pc: usize) -> Option<BranchType> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::enums::SourceType;
+    use crate::opcodes::SetVariable;
     use hex_literal::hex;
     use pretty_assertions::assert_eq;
 
@@ -251,4 +265,46 @@ mod test {
 "#;
         assert_eq!(printed, expected);
     }
+
+    #[test]
+    fn branch_targets() {
+        let bin = RcxBin::parse(PROG).unwrap();
+        let targets = disasm_code_section(&bin.sections[2].data)
+            .into_iter()
+            .filter_map(|instr| Some((instr.offset, instr.branch_target?)))
+            .map(|(offset, target)| (offset, target.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            [
+                (0x13, "21".to_string()),
+                (0x1f, "2d".to_string()),
+                (0x21, "2d".to_string()),
+                (0x31, "0a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn extended_sources() {
+        // RCX 2.0: var 0 = counter 1, var 1 = battery level
+        let instrs =
+            disasm_code_section(&hex!("14 00 15 01 00 14 01 22 00 00"));
+        assert_eq!(instrs.len(), 2);
+        assert!(matches!(
+            instrs[0].opcode,
+            Opcodes::SetVariable(SetVariable {
+                index: 0,
+                source: SourceType::Other(21),
+                argument: 1,
+            })
+        ));
+        assert!(matches!(
+            instrs[1].opcode,
+            Opcodes::SetVariable(SetVariable {
+                source: SourceType::Other(34),
+                ..
+            })
+        ));
+    }
 }
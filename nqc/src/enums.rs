@@ -3,40 +3,127 @@
 use crate::Error;
 use std::ops::BitOr;
 
+/// Convert between a fieldless enum and the byte that encodes it in
+/// requests and bytecode, rejecting bytes that name no variant
+macro_rules! byte_enum {
+    ($ty:ident, $error:literal, [$($variant:ident),+ $(,)?]) => {
+        impl TryFrom<u8> for $ty {
+            type Error = Error;
+            fn try_from(value: u8) -> Result<Self, Self::Error> {
+                [$(Self::$variant),+]
+                    .into_iter()
+                    .find(|variant| *variant as u8 == value)
+                    .ok_or(Error::InvalidData($error))
+            }
+        }
+
+        impl TryFrom<$ty> for u8 {
+            type Error = Error;
+            fn try_from(value: $ty) -> Result<Self, Self::Error> {
+                Ok(value as u8)
+            }
+        }
+    };
+}
+
 /// This section describes the available sources and arguments.
 ///
 /// Sources are like addressing modes. They specify where and how to get certain operand values.
 ///
-/// There are 16 sources available, of which 13 apply to the RCX
-#[repr(u8)]
+/// There are 16 sources available, of which 13 apply to the RCX and
+/// 3 to the CyberMaster only.
+///
+/// RCX 2.0, Scout and Spybotics firmware add further sources above 15,
+/// which are kept as [`SourceType::Other`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SourceType {
     /// Returns value of specified variable.
-    Variable = 0,
+    Variable,
     /// Returns value of specified timer, in 1/100ths of a second.
-    Timer = 1,
+    Timer,
     /// Returns specified immediate value.
-    Immediate = 2,
+    Immediate,
     /// Returns state of specified motor. See below.
-    MotorState = 3,
+    MotorState,
     /// Returns random value, 0..max.
-    Random = 4,
-    // 5,6,7 reserved for Cybermaster
+    Random,
+    /// CyberMaster only. Returns the rotation count of the specified
+    /// motor, as measured by its tachometer.
+    TachoCount,
+    /// CyberMaster only. Returns the speed of the specified motor, as
+    /// measured by its tachometer.
+    TachoSpeed,
+    /// CyberMaster only. Returns the current drawn by the specified
+    /// motor.
+    MotorCurrent,
     /// Returns current program number.
-    CurrentProgram = 8,
+    CurrentProgram,
     /// Returns value of specified sensor.
-    SensorValue = 9,
+    SensorValue,
     /// Returns type of specified sensor.
-    SensorType = 10,
+    SensorType,
     /// Returns mode of specified sensor.
-    SensorMode = 11,
+    SensorMode,
     /// Returns raw value of specified sensor, 0..1023.
-    RawSensorValue = 12,
+    RawSensorValue,
     /// Returns boolean value of specified sensor, 0..1.
-    BooleanSensorValue = 13,
+    BooleanSensorValue,
     /// Returns minutes since power on.
-    Clock = 14,
+    Clock,
     /// Returns value of message buffer.
-    Message = 15,
+    Message,
+    /// A source above 15, defined by later firmware
+    Other(u8),
+}
+
+impl SourceType {
+    /// The sources 0-15, indexed by their byte
+    const NUMBERED: [Self; 16] = [
+        Self::Variable,
+        Self::Timer,
+        Self::Immediate,
+        Self::MotorState,
+        Self::Random,
+        Self::TachoCount,
+        Self::TachoSpeed,
+        Self::MotorCurrent,
+        Self::CurrentProgram,
+        Self::SensorValue,
+        Self::SensorType,
+        Self::SensorMode,
+        Self::RawSensorValue,
+        Self::BooleanSensorValue,
+        Self::Clock,
+        Self::Message,
+    ];
+}
+
+impl TryFrom<u8> for SourceType {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(Self::NUMBERED
+            .get(usize::from(value))
+            .copied()
+            .unwrap_or(Self::Other(value)))
+    }
+}
+
+impl TryFrom<SourceType> for u8 {
+    type Error = Error;
+    fn try_from(value: SourceType) -> Result<Self, Self::Error> {
+        match value {
+            SourceType::Other(byte)
+                if usize::from(byte) < SourceType::NUMBERED.len() =>
+            {
+                Err(Error::InvalidData("Sources 0-15 have their own variants"))
+            }
+            SourceType::Other(byte) => Ok(byte),
+            source => Ok(SourceType::NUMBERED
+                .iter()
+                .position(|numbered| *numbered == source)
+                .unwrap_or_default() as u8),
+        }
+    }
 }
 
 /// Motor state is encoded as a single byte. Bits 0-2 contain the motor
@@ -49,6 +136,7 @@ pub enum SourceType {
 ///```
 /// If both bit 0x40 and bit 0x80 are 0, the specified motor is set to
 /// float.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MotorState {
     pub power: u8,
     pub direction: MotorDirection,
@@ -68,12 +156,15 @@ pub enum MotorPowerState {
     Float,
 }
 
+/// Bit of motor states and on/off codes set for motors which are off
+const OFF_FLAG: u8 = 0x40;
+/// Bit of motor states and on/off codes set for motors which are on
+const ON_FLAG: u8 = 0x80;
+
 impl TryFrom<u8> for MotorState {
     type Error = Error;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         const DIRECTION_BIT: u8 = 0x08;
-        const OFF_FLAG: u8 = 0x40;
-        const ON_FLAG: u8 = 0x80;
 
         let power = value & 0b0111;
 
@@ -87,7 +178,11 @@ impl TryFrom<u8> for MotorState {
             (0, 0) => MotorPowerState::Float,
             (_, 0) => MotorPowerState::Off,
             (0, _) => MotorPowerState::On,
-            _ => panic!("Invalid motor power state"),
+            _ => {
+                return Err(Error::InvalidData(
+                    "Motor state has both on and off flags set",
+                ))
+            }
         };
 
         Ok(Self {
@@ -98,6 +193,105 @@ impl TryFrom<u8> for MotorState {
     }
 }
 
+/// How `SetMotorDirection` changes the direction of the motors
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MotorDirectionChange {
+    Forward,
+    Reverse,
+    /// Reverse the current direction of each motor
+    Flip,
+}
+
+impl From<MotorDirection> for MotorDirectionChange {
+    fn from(direction: MotorDirection) -> Self {
+        match direction {
+            MotorDirection::Forward => Self::Forward,
+            MotorDirection::Reverse => Self::Reverse,
+        }
+    }
+}
+
+/// The code of `SetMotorDirection`. Bits 0-2 select the motors; bit
+/// 0x40 flips their direction, otherwise bit 0x80 sets them forward or
+/// its absence sets them in reverse.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MotorDirectionCode {
+    pub motors: MotorSelection,
+    pub direction: MotorDirectionChange,
+}
+
+const FLIP_FLAG: u8 = 0x40;
+const FORWARD_FLAG: u8 = 0x80;
+
+impl TryFrom<u8> for MotorDirectionCode {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let direction = if value & FLIP_FLAG != 0 {
+            MotorDirectionChange::Flip
+        } else if value & FORWARD_FLAG != 0 {
+            MotorDirectionChange::Forward
+        } else {
+            MotorDirectionChange::Reverse
+        };
+        Ok(Self {
+            motors: MotorSelection::try_from(
+                value & !(FLIP_FLAG | FORWARD_FLAG),
+            )?,
+            direction,
+        })
+    }
+}
+
+impl TryFrom<MotorDirectionCode> for u8 {
+    type Error = Error;
+    fn try_from(value: MotorDirectionCode) -> Result<Self, Self::Error> {
+        let flags = match value.direction {
+            MotorDirectionChange::Forward => FORWARD_FLAG,
+            MotorDirectionChange::Reverse => 0,
+            MotorDirectionChange::Flip => FLIP_FLAG,
+        };
+        Ok(u8::try_from(value.motors)? | flags)
+    }
+}
+
+/// The code of `SetMotorOnOff`. Bits 0-2 select the motors; bit 0x80
+/// turns them on, otherwise bit 0x40 turns them off or its absence lets
+/// them float.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MotorOnOffCode {
+    pub motors: MotorSelection,
+    pub state: MotorPowerState,
+}
+
+impl TryFrom<u8> for MotorOnOffCode {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let state = if value & ON_FLAG != 0 {
+            MotorPowerState::On
+        } else if value & OFF_FLAG != 0 {
+            MotorPowerState::Off
+        } else {
+            MotorPowerState::Float
+        };
+        Ok(Self {
+            motors: MotorSelection::try_from(value & !(OFF_FLAG | ON_FLAG))?,
+            state,
+        })
+    }
+}
+
+impl TryFrom<MotorOnOffCode> for u8 {
+    type Error = Error;
+    fn try_from(value: MotorOnOffCode) -> Result<Self, Self::Error> {
+        let flags = match value.state {
+            MotorPowerState::On => ON_FLAG,
+            MotorPowerState::Off => OFF_FLAG,
+            MotorPowerState::Float => 0,
+        };
+        Ok(u8::try_from(value.motors)? | flags)
+    }
+}
+
 /**
 There are six avaiable sound types:
 ```text
@@ -110,6 +304,7 @@ There are six avaiable sound types:
     5	Fast upward tones
 ```
 */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Sound {
     Blip = 0,
@@ -120,7 +315,20 @@ pub enum Sound {
     FastUpwardTones = 5,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+byte_enum!(
+    Sound,
+    "Sound must be 0-5",
+    [
+        Blip,
+        BeepBeep,
+        DownwardTones,
+        UpwardTones,
+        LowBuzz,
+        FastUpwardTones,
+    ]
+);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MotorSelection {
     pub bitfield: u8,
 }
@@ -140,6 +348,64 @@ impl BitOr for MotorSelection {
     }
 }
 
+impl TryFrom<u8> for MotorSelection {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value & !(Self::A | Self::B | Self::C).bitfield != 0 {
+            return Err(Error::InvalidData("Motors must be a subset of A-C"));
+        }
+        Ok(Self { bitfield: value })
+    }
+}
+
+impl TryFrom<MotorSelection> for u8 {
+    type Error = Error;
+    fn try_from(value: MotorSelection) -> Result<Self, Self::Error> {
+        MotorSelection::try_from(value.bitfield).map(|motors| motors.bitfield)
+    }
+}
+
+/// Buttons of the LEGO remote control, combined with `|`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RemoteButtons {
+    pub bitfield: u16,
+}
+
+impl RemoteButtons {
+    /// No buttons held, releasing any previously held
+    pub const NONE: Self = Self { bitfield: 0x0000 };
+    pub const MESSAGE_1: Self = Self { bitfield: 0x0100 };
+    pub const MESSAGE_2: Self = Self { bitfield: 0x0200 };
+    pub const MESSAGE_3: Self = Self { bitfield: 0x0400 };
+    pub const MOTOR_A_FORWARD: Self = Self { bitfield: 0x0800 };
+    pub const MOTOR_B_FORWARD: Self = Self { bitfield: 0x1000 };
+    pub const MOTOR_C_FORWARD: Self = Self { bitfield: 0x2000 };
+    pub const MOTOR_A_REVERSE: Self = Self { bitfield: 0x4000 };
+    pub const MOTOR_B_REVERSE: Self = Self { bitfield: 0x8000 };
+    pub const MOTOR_C_REVERSE: Self = Self { bitfield: 0x0001 };
+    pub const PROGRAM_1: Self = Self { bitfield: 0x0002 };
+    pub const PROGRAM_2: Self = Self { bitfield: 0x0004 };
+    pub const PROGRAM_3: Self = Self { bitfield: 0x0008 };
+    pub const PROGRAM_4: Self = Self { bitfield: 0x0010 };
+    pub const PROGRAM_5: Self = Self { bitfield: 0x0020 };
+    /// Stop all programs and motors
+    pub const STOP: Self = Self { bitfield: 0x0040 };
+    pub const SOUND: Self = Self { bitfield: 0x0080 };
+
+    pub fn contains(self, other: Self) -> bool {
+        self.bitfield & other.bitfield == other.bitfield
+    }
+}
+
+impl BitOr for RemoteButtons {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self {
+            bitfield: self.bitfield | rhs.bitfield,
+        }
+    }
+}
+
 /**
     Set the slope and mode of sensor number sensor to the value specified by mode, and clear that sensor's value. The bits of mode are split into two portions. Bits 0-4 contain a slope value in 0..31, while bits 5-7 contain the mode, 0..7. The eight modes, which control the value returned by the sensor, are:
     ```text
@@ -169,6 +435,50 @@ pub enum SensorMode {
     Angle,
 }
 
+byte_enum!(
+    SensorMode,
+    "Sensor mode must be 0-7",
+    [
+        Raw,
+        Boolean,
+        EdgeCount,
+        PulseCount,
+        Percentage,
+        TemperatureC,
+        TemperatureF,
+        Angle,
+    ]
+);
+
+/// The argument of `SetSensorMode`: a mode in bits 5-7 and a slope in
+/// bits 0-4, as described for [`SensorMode`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SensorModeCode {
+    pub mode: SensorMode,
+    /// 0 for the default hysteresis, or 1-31
+    pub slope: u8,
+}
+
+impl TryFrom<u8> for SensorModeCode {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(Self {
+            mode: SensorMode::try_from(value >> 5)?,
+            slope: value & 0x1f,
+        })
+    }
+}
+
+impl TryFrom<SensorModeCode> for u8 {
+    type Error = Error;
+    fn try_from(value: SensorModeCode) -> Result<Self, Self::Error> {
+        if value.slope > 0x1f {
+            return Err(Error::InvalidData("Sensor slope must be 0-31"));
+        }
+        Ok((value.mode as u8) << 5 | value.slope)
+    }
+}
+
 /**
     ```text
         Type	Description	Default Mode
@@ -189,6 +499,115 @@ pub enum SensorType {
     Rotation,
 }
 
+byte_enum!(
+    SensorType,
+    "Sensor type must be 0-4",
+    [Raw, Touch, Temperature, Light, Rotation]
+);
+
+/// Whether the Scout follows its own rules or is controlled by the PC
+/// or a downloaded program
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScoutMode {
+    Standalone = 0,
+    Power = 1,
+}
+
+byte_enum!(ScoutMode, "Invalid Scout mode", [Standalone, Power]);
+
+/// How the Scout moves in stand-alone mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScoutMotion {
+    None = 0,
+    Forward,
+    Zigzag,
+    CircleRight,
+    CircleLeft,
+    LoopA,
+    LoopB,
+    LoopAB,
+}
+
+byte_enum!(
+    ScoutMotion,
+    "Invalid Scout motion rule",
+    [
+        None,
+        Forward,
+        Zigzag,
+        CircleRight,
+        CircleLeft,
+        LoopA,
+        LoopB,
+        LoopAB,
+    ]
+);
+
+/// How the Scout reacts to its touch sensors in stand-alone mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScoutTouch {
+    Ignore = 0,
+    Reverse,
+    Avoid,
+    WaitFor,
+    OffWhen,
+}
+
+byte_enum!(
+    ScoutTouch,
+    "Invalid Scout touch rule",
+    [Ignore, Reverse, Avoid, WaitFor, OffWhen]
+);
+
+/// How the Scout reacts to its light sensor in stand-alone mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScoutLight {
+    Ignore = 0,
+    SeekLight,
+    SeekDark,
+    Avoid,
+    WaitFor,
+    OffWhen,
+}
+
+byte_enum!(
+    ScoutLight,
+    "Invalid Scout light rule",
+    [Ignore, SeekLight, SeekDark, Avoid, WaitFor, OffWhen]
+);
+
+/// Time base of the Scout's stand-alone rules
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScoutTime {
+    Short = 0,
+    Medium,
+    Long,
+}
+
+byte_enum!(ScoutTime, "Invalid Scout time rule", [Short, Medium, Long]);
+
+/// Special effect played by the Scout in stand-alone mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScoutEffect {
+    None = 0,
+    Bug,
+    Alarm,
+    Random,
+    Science,
+}
+
+byte_enum!(
+    ScoutEffect,
+    "Invalid Scout effect",
+    [None, Bug, Alarm, Random, Science]
+);
+
 /// Set the transmitter range. 0 indicates short range, 1 indicates long
 /// range. Other values are ignored.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -197,3 +616,55 @@ pub enum TransmitterRange {
     Short = 0,
     Long = 1,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn motor_state() {
+        assert_eq!(
+            MotorState::try_from(0x8d).unwrap(),
+            MotorState {
+                power: 5,
+                direction: MotorDirection::Reverse,
+                state: MotorPowerState::On,
+            }
+        );
+        assert_eq!(
+            MotorState::try_from(0x02).unwrap().state,
+            MotorPowerState::Float
+        );
+        assert!(MotorState::try_from(0xc0).is_err());
+    }
+
+    #[test]
+    fn byte_conversions() {
+        assert_eq!(SourceType::try_from(6).unwrap(), SourceType::TachoSpeed);
+        assert_eq!(SourceType::try_from(34).unwrap(), SourceType::Other(34));
+        assert_eq!(u8::try_from(SourceType::Message).unwrap(), 15);
+        assert_eq!(u8::try_from(SourceType::Other(34)).unwrap(), 34);
+        assert!(u8::try_from(SourceType::Other(6)).is_err());
+        assert!(Sound::try_from(6).is_err());
+
+        let code = SensorModeCode {
+            mode: SensorMode::Boolean,
+            slope: 10,
+        };
+        assert_eq!(u8::try_from(code).unwrap(), 0x2a);
+        assert_eq!(SensorModeCode::try_from(0x2a).unwrap(), code);
+        assert!(u8::try_from(SensorModeCode { slope: 32, ..code }).is_err());
+
+        let code = MotorDirectionCode::try_from(0xc5).unwrap();
+        assert_eq!(code.motors, MotorSelection::A | MotorSelection::C);
+        assert_eq!(code.direction, MotorDirectionChange::Flip);
+        assert_eq!(u8::try_from(code).unwrap(), 0x45);
+        let code = MotorOnOffCode::try_from(0x42).unwrap();
+        assert_eq!(code.state, MotorPowerState::Off);
+        assert_eq!(u8::try_from(code).unwrap(), 0x42);
+        assert!(MotorOnOffCode::try_from(0x88).is_err());
+
+        assert!(MotorSelection::try_from(0x08).is_err());
+        assert!(u8::try_from(MotorSelection { bitfield: 0x10 }).is_err());
+    }
+}
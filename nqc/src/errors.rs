@@ -1,3 +1,5 @@
+use crate::binfmt::SectionType;
+
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
@@ -14,6 +16,9 @@ pub enum Error {
     #[error("Timeout was reached")]
     Timeout,
 
+    #[error("No reply from the brick after {0} attempts")]
+    NoReply(usize),
+
     #[error("Checksum mismatch")]
     Checksum,
 
@@ -34,6 +39,37 @@ pub enum Error {
 
     #[error("Invalid opcode: 0x{0:02x}")]
     InvalidOpcode(u8),
+
+    #[error("Tower server error: {0}")]
+    Remote(String),
+
+    #[error("Not enough memory on the brick")]
+    OutOfMemory,
+
+    #[error("Task or subroutine number was rejected by the brick")]
+    IllegalIndex,
+
+    #[error("Data block checksum failure")]
+    BlockChecksum,
+
+    #[error("Firmware checksum error")]
+    FirmwareChecksum,
+
+    #[error("No download in progress")]
+    NoDownloadInProgress,
+
+    #[error("Unknown download error code {0}")]
+    UnknownDownloadError(u8),
+
+    #[error("Failed to download {ty} {number}: {source}")]
+    SectionDownload {
+        ty: SectionType,
+        number: u8,
+        source: Box<Error>,
+    },
+
+    #[error("Line {line}: {source}")]
+    Asm { line: usize, source: Box<Error> },
 }
 
 impl<T: std::fmt::Debug> From<nom::Err<T>> for Error {
@@ -3,6 +3,8 @@ pub mod binfmt;
 pub mod disasm;
 pub mod nqc;
 pub mod opcodes;
+pub mod packet;
+pub mod srec;
 
 mod display_impls;
 pub mod enums;
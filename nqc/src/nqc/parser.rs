@@ -1,7 +1,7 @@
 use lalrpop_util::lalrpop_mod;
 
 lalrpop_mod!(
-    #[allow(clippy::ptr_arg)]
+    #[allow(clippy::ptr_arg, clippy::vec_box)]
     #[rustfmt::skip]
 	nqc
 );
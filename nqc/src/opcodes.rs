@@ -1,22 +1,18 @@
-use crate::{Error, Result};
+use crate::{enums, packet::Packet, Error, Result};
 use std::{
     fmt::{self, Debug, Display, Formatter},
-    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    io::{Cursor, Read, Write},
 };
 
-fn is_header(byte: u8) -> bool {
-    [0x00, 0x55, 0xff].contains(&byte)
-}
-
 trait WriteParam {
-    fn write_param(&self, buf: impl Write) -> io::Result<()>;
+    fn write_param(&self, buf: impl Write) -> Result<()>;
 }
 
 macro_rules! writeparamimpl {
     ($ty:ty) => {
         impl WriteParam for $ty {
-            fn write_param(&self, mut buf: impl Write) -> io::Result<()> {
-                buf.write_all(&self.to_le_bytes())
+            fn write_param(&self, mut buf: impl Write) -> Result<()> {
+                Ok(buf.write_all(&self.to_le_bytes())?)
             }
         }
     };
@@ -28,50 +24,43 @@ writeparamimpl!(u16);
 writeparamimpl!(i16);
 
 impl<const N: usize> WriteParam for [u8; N] {
-    fn write_param(&self, mut buf: impl Write) -> io::Result<()> {
-        buf.write_all(self)
+    fn write_param(&self, mut buf: impl Write) -> Result<()> {
+        Ok(buf.write_all(self)?)
     }
 }
 
 impl WriteParam for Vec<u8> {
-    fn write_param(&self, mut buf: impl Write) -> io::Result<()> {
-        buf.write_all(self)
+    fn write_param(&self, mut buf: impl Write) -> Result<()> {
+        Ok(buf.write_all(self)?)
     }
 }
 
 trait ReadParam {
+    /// Number of bytes the parameter is encoded in, or `None` if it takes
+    /// the rest of the payload
+    const LEN: Option<usize>;
+
     fn read_param(buf: &mut impl Read) -> Result<Self>
     where
         Self: Sized;
 }
 
+/// Sum of parameter lengths, `None` if any of them is not fixed
+const fn add_len(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        _ => None,
+    }
+}
+
 macro_rules! readparamimpl {
     ($ty:ty) => {
         impl ReadParam for $ty {
-            fn read_param(buf: &mut impl Read) -> Result<Self> {
-                const BYTES: usize = (<$ty>::BITS / 8) as usize;
-
-                // read twice the requested number of bytes due to the
-                // complement encoding
-                let mut double_bytes = [0; BYTES * 2];
-                buf.read_exact(&mut double_bytes)?;
-
-                println!("parse: {:02x?}", double_bytes);
-
-                // deduplicate the complements
-                let mut bytes = [0; BYTES];
-                for idx in 0..BYTES {
-                    bytes[idx] = double_bytes[2 * idx];
-                    if bytes[idx] != !double_bytes[2 * idx + 1] {
-                        println!(
-                            "{:02x} != {:02x}",
-                            bytes[idx],
-                            !double_bytes[2 * idx + 1]
-                        );
-                        return Err(Error::Checksum);
-                    }
-                }
+            const LEN: Option<usize> = Some((<$ty>::BITS / 8) as usize);
 
+            fn read_param(buf: &mut impl Read) -> Result<Self> {
+                let mut bytes = [0; (<$ty>::BITS / 8) as usize];
+                buf.read_exact(&mut bytes)?;
                 Ok(Self::from_le_bytes(bytes))
             }
         }
@@ -153,35 +142,105 @@ impl<const N: usize> DisasmParam for [u8; N] {
     }
 }
 
-trait AddToChecksum {
-    fn add_to_checksum(&self, checksum: &mut u8);
+/// Parameters given as operands to the assembler, each taking as many
+/// operands as it has values
+trait AsmParam {
+    fn asm_param(args: &mut impl Iterator<Item = i32>) -> Result<Self>
+    where
+        Self: Sized;
 }
 
-macro_rules! add_to_checksum_impl {
+fn next_arg(args: &mut impl Iterator<Item = i32>) -> Result<i32> {
+    args.next().ok_or(Error::Parse("Missing argument"))
+}
+
+macro_rules! asmparamimpl {
     ($ty:ty) => {
-        impl AddToChecksum for $ty {
-            fn add_to_checksum(&self, checksum: &mut u8) {
-                for byte in self.to_be_bytes() {
-                    *checksum = checksum.wrapping_add(byte);
-                }
+        impl AsmParam for $ty {
+            fn asm_param(args: &mut impl Iterator<Item = i32>) -> Result<Self> {
+                Ok(Self::try_from(next_arg(args)?)?)
             }
         }
     };
 }
 
-add_to_checksum_impl!(u8);
-add_to_checksum_impl!(u16);
-add_to_checksum_impl!(i16);
+asmparamimpl!(u8);
+asmparamimpl!(i8);
+asmparamimpl!(u16);
+asmparamimpl!(i16);
 
-impl<const N: usize, T: AddToChecksum> AddToChecksum for [T; N] {
-    fn add_to_checksum(&self, checksum: &mut u8) {
-        for ele in self {
-            ele.add_to_checksum(checksum);
+impl<const N: usize, T: AsmParam + Default + Copy> AsmParam for [T; N] {
+    fn asm_param(args: &mut impl Iterator<Item = i32>) -> Result<Self> {
+        let mut ret = [T::default(); N];
+        for item in ret.iter_mut() {
+            *item = T::asm_param(args)?;
         }
+        Ok(ret)
+    }
+}
+
+/// Variable-length parameters take up the remaining operands
+impl AsmParam for Vec<u8> {
+    fn asm_param(args: &mut impl Iterator<Item = i32>) -> Result<Self> {
+        Ok(args
+            .map(u8::try_from)
+            .collect::<core::result::Result<_, _>>()?)
     }
 }
 
+/// Typed parameters encoded as a single byte, of which only some values
+/// are valid. The conversion is checked in both directions, so that
+/// neither invalid requests nor invalid bytecode go unnoticed.
+macro_rules! byteparamimpl {
+    ($ty:ty) => {
+        impl WriteParam for $ty {
+            fn write_param(&self, buf: impl Write) -> Result<()> {
+                u8::try_from(*self)?.write_param(buf)
+            }
+        }
+
+        impl ReadParam for $ty {
+            const LEN: Option<usize> = u8::LEN;
+
+            fn read_param(buf: &mut impl Read) -> Result<Self> {
+                Self::try_from(u8::read_param(buf)?)
+            }
+        }
+
+        impl DisasmParam for $ty {
+            fn disasm_param(bin: &[u8], pc: &mut usize) -> Result<Self> {
+                Self::try_from(read_byte(bin, pc)?)
+            }
+        }
+
+        impl AsmParam for $ty {
+            fn asm_param(args: &mut impl Iterator<Item = i32>) -> Result<Self> {
+                Self::try_from(u8::asm_param(args)?)
+            }
+        }
+    };
+}
+
+byteparamimpl!(enums::MotorDirectionCode);
+byteparamimpl!(enums::MotorOnOffCode);
+byteparamimpl!(enums::MotorSelection);
+byteparamimpl!(enums::ScoutEffect);
+byteparamimpl!(enums::ScoutLight);
+byteparamimpl!(enums::ScoutMode);
+byteparamimpl!(enums::ScoutMotion);
+byteparamimpl!(enums::ScoutTime);
+byteparamimpl!(enums::ScoutTouch);
+byteparamimpl!(enums::SensorModeCode);
+byteparamimpl!(enums::SensorType);
+byteparamimpl!(enums::Sound);
+byteparamimpl!(enums::SourceType);
+
 impl<const N: usize, T: ReadParam + Default + Copy> ReadParam for [T; N] {
+    const LEN: Option<usize> = match T::LEN {
+        Some(len) => Some(len * N),
+        None => None,
+    };
+
     fn read_param(buf: &mut impl Read) -> Result<Self>
     where
         Self: Sized,
@@ -195,9 +254,35 @@ impl<const N: usize, T: ReadParam + Default + Copy> ReadParam for [T; N] {
     }
 }
 
+/// Variable-length parameters take up the remainder of the payload
+impl ReadParam for Vec<u8> {
+    const LEN: Option<usize> = None;
+
+    fn read_param(buf: &mut impl Read) -> Result<Self> {
+        let mut ret = Vec::new();
+        buf.read_to_end(&mut ret)?;
+        Ok(ret)
+    }
+}
+
 pub trait Opcode: Debug + Display {
+    /// The parsed reply: a `*Response` struct for replies which carry
+    /// parameters, otherwise `()`
+    type Response
+    where
+        Self: Sized;
+
     fn request_opcode(&self) -> u8;
     fn response_opcode(&self) -> Option<u8>;
+    /// Parse the reply to this request, as returned by
+    /// `IrTower::send_recv`
+    fn parse_response(reply: &[u8]) -> Result<Self::Response>
+    where
+        Self: Sized;
+    /// Whether the request may be sent with bit 0x08 of its opcode set
+    fn supports_alternate(&self) -> bool {
+        true
+    }
     fn serialise(&self, buf: &mut [u8]) -> Result<usize>;
     fn disasm(bin: &[u8], pc: &mut usize) -> Result<Self>
     where
@@ -222,12 +307,45 @@ mod test {
 
     #[test]
     fn play_sound_ser() {
-        let op = PlaySound { sound: 2 };
+        let op = PlaySound {
+            sound: enums::Sound::DownwardTones,
+        };
         let mut buf = [0; 100];
         let len = op.serialise(&mut buf).unwrap();
         assert_eq!(&buf[..len], &[2]);
     }
 
+    #[test]
+    fn typed_params() {
+        let op = SetSensorMode {
+            sensor: 1,
+            code: enums::SensorModeCode {
+                mode: enums::SensorMode::Percentage,
+                slope: 0,
+            },
+        };
+        let mut buf = [0; 100];
+        let len = op.serialise(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[1, 0x80]);
+
+        let op = SetMotorPower {
+            motors: enums::MotorSelection { bitfield: 0x08 },
+            source: enums::SourceType::Immediate,
+            argument: 7,
+        };
+        assert!(op.serialise(&mut buf).is_err());
+
+        let mut pc = 0;
+        assert!(matches!(
+            parse_opcode(&[0x51, 0x03], &mut pc),
+            Ok(Opcodes::PlaySound(PlaySound {
+                sound: enums::Sound::UpwardTones
+            }))
+        ));
+        let mut pc = 0;
+        assert!(parse_opcode(&[0x51, 0x06], &mut pc).is_err());
+    }
+
     #[test]
     fn battery_response() {
         let buf = &[
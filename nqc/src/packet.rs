@@ -0,0 +1,517 @@
+//! Encoder and decoder for the RCX IR packet format
+//!
+//! ```text
+//! * header - 0x55 0xff 0x00
+//! * opcode - 1 byte, followed by its complement
+//! * payload - each byte followed by its complement
+//! * checksum - sum of opcode and payload bytes, followed by its
+//!   complement
+//! ```
+//!
+//! Requests may set bit 0x08 of the opcode (the "alternate" form) so
+//! that the brick can distinguish a retransmission from a new request
+//! with the same opcode. The reply opcode is the complement of the
+//! request opcode as transmitted.
+//!
+//! The CyberMaster uses the same packets behind a different header.
+//! Spybotics keeps the header but sends each byte once, and its checksum
+//! makes the opcode, payload and checksum sum to zero. Protocols with
+//! framing of their own, such as LNP, are sent raw. See [`Framing`].
+
+use crate::{
+    opcodes::{self, Opcode},
+    Error, Result,
+};
+use std::fmt::{self, Display, Formatter};
+
+pub const HEADER: [u8; 3] = [0x55, 0xff, 0x00];
+
+/// Header of packets exchanged with a CyberMaster through its RF tower
+pub const CYBERMASTER_HEADER: [u8; 4] = [0xfe, 0x00, 0x00, 0xff];
+
+/// Bit set in the alternate form of a request opcode
+pub const ALTERNATE_BIT: u8 = 0x08;
+
+/// Largest serialised request, in bytes
+const MAX_PAYLOAD: usize = 1024;
+
+/// How packets are framed on the wire, which depends on the brick being
+/// addressed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// RCX and Scout
+    #[default]
+    Rcx,
+    CyberMaster,
+    Spybotics,
+    /// The opcode and payload are sent as they are, without header or
+    /// checksum, for protocols which frame packets themselves
+    Raw,
+}
+
+impl Framing {
+    pub fn header(self) -> &'static [u8] {
+        match self {
+            Self::Rcx | Self::Spybotics => &HEADER,
+            Self::CyberMaster => &CYBERMASTER_HEADER,
+            Self::Raw => &[],
+        }
+    }
+
+    /// Whether each byte is followed by its complement
+    fn complements(self) -> bool {
+        matches!(self, Self::Rcx | Self::CyberMaster)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(opcode: u8, payload: Vec<u8>) -> Self {
+        Self { opcode, payload }
+    }
+
+    /// Build the packet for a request, using the alternate form of its
+    /// opcode if requested and supported
+    pub fn request(msg: &dyn Opcode, alternate: bool) -> Result<Self> {
+        let mut opcode = msg.request_opcode();
+        if alternate && msg.supports_alternate() {
+            opcode |= ALTERNATE_BIT;
+        }
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = msg.serialise(&mut buf)?;
+        Ok(Self::new(opcode, buf[..len].to_vec()))
+    }
+
+    /// The opcode of the reply the brick sends for this request
+    pub fn reply_opcode(&self) -> u8 {
+        !self.opcode
+    }
+
+    /// Whether this packet is the reply to a request sent with
+    /// `request_opcode`, including its alternate bit
+    pub fn is_reply_to(&self, request_opcode: u8) -> bool {
+        self.opcode == !request_opcode
+    }
+
+    pub fn checksum(&self) -> u8 {
+        self.payload
+            .iter()
+            .fold(self.opcode, |sum, &byte| sum.wrapping_add(byte))
+    }
+
+    /// The checksum used by `framing`
+    pub fn checksum_framed(&self, framing: Framing) -> u8 {
+        match framing {
+            Framing::Spybotics => self.checksum().wrapping_neg(),
+            _ => self.checksum(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_framed(Framing::Rcx)
+    }
+
+    /// Encode the packet behind the header used by `framing`
+    pub fn encode_framed(&self, framing: Framing) -> Vec<u8> {
+        let header = framing.header();
+        let mut out =
+            Vec::with_capacity(header.len() + 2 * (self.payload.len() + 2));
+        out.extend_from_slice(header);
+        let checksum =
+            (framing != Framing::Raw).then(|| self.checksum_framed(framing));
+        for &byte in std::iter::once(&self.opcode)
+            .chain(&self.payload)
+            .chain(checksum.as_ref())
+        {
+            out.push(byte);
+            if framing.complements() {
+                out.push(!byte);
+            }
+        }
+        out
+    }
+
+    /// Decode the first valid packet in `buf`, skipping any leading
+    /// noise
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        Self::decode_framed(buf, Framing::Rcx)
+    }
+
+    /// Decode the first valid packet in `buf` framed as `framing`
+    pub fn decode_framed(buf: &[u8], framing: Framing) -> Result<Self> {
+        let (packets, checksum_failed) = scan(buf, framing);
+        packets.into_iter().next().ok_or(if checksum_failed {
+            Error::Checksum
+        } else {
+            Error::InsufficientData
+        })
+    }
+
+    /// Decode every valid packet in `buf`, in order, skipping noise
+    /// between them
+    pub fn decode_all(buf: &[u8]) -> Vec<Self> {
+        Self::decode_all_framed(buf, Framing::Rcx)
+    }
+
+    /// Decode every valid packet in `buf` framed as `framing`
+    pub fn decode_all_framed(buf: &[u8], framing: Framing) -> Vec<Self> {
+        scan(buf, framing).0
+    }
+
+    /// Decode the reply to a request sent with `request_opcode`,
+    /// skipping any echo of the request itself, as heard by towers
+    /// which receive their own transmissions
+    pub fn decode_reply(buf: &[u8], request_opcode: u8) -> Result<Self> {
+        Self::decode_reply_framed(buf, request_opcode, Framing::Rcx)
+    }
+
+    /// Decode the reply to a request sent with `request_opcode`, framed
+    /// as `framing`
+    pub fn decode_reply_framed(
+        buf: &[u8],
+        request_opcode: u8,
+        framing: Framing,
+    ) -> Result<Self> {
+        let packets = Self::decode_all_framed(buf, framing);
+        if let Some(reply) = packets
+            .iter()
+            .find(|packet| packet.is_reply_to(request_opcode))
+        {
+            Ok(reply.clone())
+        } else if let Some(other) = packets
+            .iter()
+            .find(|packet| packet.opcode != request_opcode)
+        {
+            Err(Error::InvalidOpcode(other.opcode))
+        } else {
+            // propagate the reason nothing could be decoded
+            Self::decode_framed(buf, framing).and(Err(Error::InsufficientData))
+        }
+    }
+}
+
+impl Display for Packet {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{:02x} {}", self.opcode, hex::encode(&self.payload))
+    }
+}
+
+/// Every valid packet in `buf`, in order, and whether anything was
+/// rejected for a bad checksum
+fn scan(buf: &[u8], framing: Framing) -> (Vec<Packet>, bool) {
+    if framing == Framing::Raw {
+        // the whole buffer is taken as one packet
+        let packet = buf
+            .split_first()
+            .map(|(&opcode, payload)| Packet::new(opcode, payload.to_vec()));
+        return (packet.into_iter().collect(), false);
+    }
+    if !framing.complements() {
+        return scan_uncomplemented(buf);
+    }
+
+    let mut packets = Vec::new();
+    let mut checksum_failed = false;
+    let mut pos = 0;
+    while pos < buf.len() {
+        // only a whole header is skipped, as its bytes are also valid
+        // opcodes and payload. Either brick's header may be heard.
+        if let Some(header) = [&HEADER[..], &CYBERMASTER_HEADER[..]]
+            .into_iter()
+            .find(|header| buf[pos..].starts_with(header))
+        {
+            pos += header.len();
+            continue;
+        }
+        match decode_at(&buf[pos..]) {
+            Decoded::Packet(packet, len) => {
+                packets.push(packet);
+                pos += len;
+            }
+            Decoded::BadChecksum => {
+                checksum_failed = true;
+                pos += 1;
+            }
+            Decoded::Nothing => pos += 1,
+        }
+    }
+    (packets, checksum_failed)
+}
+
+/// Without complements there is nothing to tell a packet from noise, so
+/// a packet starts after a header and ends with the first byte at which
+/// its bytes sum to zero, at the expected length if it is a reply. The
+/// search for the next header resumes after the packet, as the payload
+/// may contain the header bytes.
+fn scan_uncomplemented(buf: &[u8]) -> (Vec<Packet>, bool) {
+    let mut packets = Vec::new();
+    let mut checksum_failed = false;
+    let mut pos = 0;
+    while let Some(offset) = buf[pos..]
+        .windows(HEADER.len())
+        .position(|bytes| bytes == HEADER)
+    {
+        let start = pos + offset + HEADER.len();
+        let bytes = &buf[start..];
+        match uncomplemented_len(bytes) {
+            Some(len) => {
+                packets.push(Packet::new(bytes[0], bytes[1..len - 1].to_vec()));
+                pos = start + len;
+            }
+            None => {
+                checksum_failed |= !bytes.is_empty();
+                pos = start;
+            }
+        }
+    }
+    (packets, checksum_failed)
+}
+
+/// Length of the uncomplemented packet at the start of `bytes`,
+/// including its opcode and checksum
+fn uncomplemented_len(bytes: &[u8]) -> Option<usize> {
+    let &opcode = bytes.first()?;
+    let mut sum = 0u8;
+    let mut lens = bytes.iter().enumerate().filter_map(|(idx, &byte)| {
+        sum = sum.wrapping_add(byte);
+        (idx > 0 && sum == 0).then_some(idx + 1)
+    });
+    match opcodes::reply_len(opcode) {
+        Some(payload) => lens.find(|&len| len == payload + 2),
+        None => lens.next(),
+    }
+}
+
+enum Decoded {
+    /// A packet and the number of bytes it occupied
+    Packet(Packet, usize),
+    BadChecksum,
+    Nothing,
+}
+
+/// Try to decode a packet starting at the opcode at the start of `buf`.
+///
+/// The packet extends over the run of byte/complement pairs, and the
+/// last pair whose value matches the sum of the preceding bytes is the
+/// checksum. Noise after a packet may happen to look like valid pairs,
+/// so the longest run with a valid checksum wins.
+fn decode_at(buf: &[u8]) -> Decoded {
+    let bytes = buf
+        .chunks_exact(2)
+        .take_while(|pair| pair[0] == !pair[1])
+        .map(|pair| pair[0])
+        .collect::<Vec<_>>();
+    if bytes.len() < 2 {
+        return Decoded::Nothing;
+    }
+
+    let mut sums = Vec::with_capacity(bytes.len());
+    let mut sum = 0u8;
+    for &byte in &bytes {
+        sums.push(sum);
+        sum = sum.wrapping_add(byte);
+    }
+
+    (2..=bytes.len())
+        .rev()
+        .find(|&len| bytes[len - 1] == sums[len - 1])
+        .map_or(Decoded::BadChecksum, |len| {
+            Decoded::Packet(
+                Packet::new(bytes[0], bytes[1..len - 1].to_vec()),
+                len * 2,
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        enums::Sound,
+        opcodes::{
+            GetBatteryPower, GetVersionsResponse, PlaySound, SetMessage,
+        },
+    };
+
+    const BATTERY_REPLY: &[u8] = &[
+        0x55, 0xff, 0x00, 0xcf, 0x30, 0x43, 0xbc, 0x1e, 0xe1, 0x30, 0xcf,
+    ];
+
+    #[test]
+    fn encode_request() {
+        let packet = Packet::request(
+            &PlaySound {
+                sound: Sound::DownwardTones,
+            },
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            packet.encode(),
+            [0x55, 0xff, 0x00, 0x51, 0xae, 0x02, 0xfd, 0x53, 0xac]
+        );
+
+        let packet = Packet::request(&GetBatteryPower {}, true).unwrap();
+        assert_eq!(packet.opcode, 0x38);
+        assert_eq!(packet.reply_opcode(), 0xc7);
+
+        // 0xff would be mistaken for part of the header
+        let packet = Packet::request(&SetMessage { message: 1 }, true).unwrap();
+        assert_eq!(packet.opcode, 0xf7);
+    }
+
+    #[test]
+    fn round_trip() {
+        let packet = Packet::new(0xb2, vec![0x00, 0x55, 0xff, 0x12]);
+        assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+    }
+
+    #[test]
+    fn cybermaster_framing() {
+        let packet = Packet::request(
+            &PlaySound {
+                sound: Sound::DownwardTones,
+            },
+            false,
+        )
+        .unwrap();
+        let frame = packet.encode_framed(Framing::CyberMaster);
+        assert_eq!(
+            frame,
+            [0xfe, 0x00, 0x00, 0xff, 0x51, 0xae, 0x02, 0xfd, 0x53, 0xac]
+        );
+        assert_eq!(Packet::decode(&frame).unwrap(), packet);
+    }
+
+    #[test]
+    fn spybotics_framing() {
+        let packet = Packet::request(
+            &PlaySound {
+                sound: Sound::DownwardTones,
+            },
+            false,
+        )
+        .unwrap();
+        let frame = packet.encode_framed(Framing::Spybotics);
+        assert_eq!(frame, [0x55, 0xff, 0x00, 0x51, 0x02, 0xad]);
+        assert_eq!(
+            Packet::decode_framed(&frame, Framing::Spybotics).unwrap(),
+            packet
+        );
+
+        // echo, then the reply followed by noise
+        let reply = Packet::new(0xae, Vec::new());
+        let mut buf = frame.clone();
+        buf.extend(reply.encode_framed(Framing::Spybotics));
+        buf.push(0x99);
+        assert_eq!(
+            Packet::decode_reply_framed(&buf, 0x51, Framing::Spybotics)
+                .unwrap(),
+            reply
+        );
+
+        let mut buf = frame;
+        *buf.last_mut().unwrap() ^= 0x01;
+        assert!(matches!(
+            Packet::decode_framed(&buf, Framing::Spybotics),
+            Err(Error::Checksum)
+        ));
+    }
+
+    #[test]
+    fn spybotics_header_in_payload() {
+        // GetVersions replies with four words, the first of which is
+        // 0xff55 followed by 0x0000, so the payload starts with a header
+        let reply = Packet::new(
+            0xea,
+            vec![0x55, 0xff, 0x00, 0x00, 0x03, 0x00, 0x01, 0x00],
+        );
+        let mut buf = Packet::new(0x15, vec![1, 3, 5, 7, 11])
+            .encode_framed(Framing::Spybotics);
+        buf.extend(reply.encode_framed(Framing::Spybotics));
+        let decoded =
+            Packet::decode_reply_framed(&buf, 0x15, Framing::Spybotics)
+                .unwrap();
+        assert_eq!(decoded, reply);
+        let versions = GetVersionsResponse::from_packet(&decoded).unwrap();
+        assert_eq!(versions.rom, [-0xab, 0]);
+        assert_eq!(versions.firmware, [3, 1]);
+    }
+
+    #[test]
+    fn header_bytes_as_opcode() {
+        for opcode in HEADER {
+            let packet = Packet::new(opcode, vec![0x55, 0xff, 0x00]);
+            assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn decode_with_noise() {
+        let mut buf = vec![0x12, 0x34, 0xfe];
+        buf.extend_from_slice(BATTERY_REPLY);
+        buf.extend_from_slice(&[0x99]);
+        assert_eq!(
+            Packet::decode(&buf).unwrap(),
+            Packet::new(0xcf, vec![0x43, 0x1e])
+        );
+    }
+
+    #[test]
+    fn decode_without_header() {
+        assert_eq!(
+            Packet::decode(&BATTERY_REPLY[3..]).unwrap(),
+            Packet::new(0xcf, vec![0x43, 0x1e])
+        );
+    }
+
+    #[test]
+    fn bad_complement() {
+        let mut buf = BATTERY_REPLY.to_vec();
+        buf[6] ^= 0x01;
+        assert!(matches!(Packet::decode(&buf), Err(Error::Checksum)));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut buf = BATTERY_REPLY.to_vec();
+        buf[9] ^= 0x01;
+        buf[10] ^= 0x01;
+        assert!(matches!(Packet::decode(&buf), Err(Error::Checksum)));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(matches!(
+            Packet::decode(&HEADER),
+            Err(Error::InsufficientData)
+        ));
+    }
+
+    #[test]
+    fn echo_is_skipped() {
+        let request = Packet::request(&GetBatteryPower {}, false).unwrap();
+        let mut buf = request.encode();
+        buf.extend_from_slice(BATTERY_REPLY);
+
+        assert_eq!(Packet::decode_all(&buf).len(), 2);
+        assert_eq!(
+            Packet::decode_reply(&buf, request.opcode).unwrap(),
+            Packet::new(0xcf, vec![0x43, 0x1e])
+        );
+        // a reply to the other form of the opcode is stale
+        assert!(matches!(
+            Packet::decode_reply(BATTERY_REPLY, 0x38),
+            Err(Error::InvalidOpcode(0xcf))
+        ));
+        assert!(matches!(
+            Packet::decode_reply(&request.encode(), request.opcode),
+            Err(Error::InsufficientData)
+        ));
+    }
+}
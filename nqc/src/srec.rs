@@ -0,0 +1,192 @@
+//! Parser for Motorola S-record files, such as the `.lgo` firmware
+//! images distributed for the RCX
+//!
+//! ```text
+//! S<type><count><address><data><checksum>
+//! * type - 1 hex digit, 0-9
+//! * count - 2 hex digits, number of bytes in address + data + checksum
+//! * address - 4, 6 or 8 hex digits depending on type
+//! * data - count - address length - 1 bytes
+//! * checksum - ones' complement of the sum of count, address and data
+//! ```
+//!
+//! Data records (S1-S3) are flattened into a single contiguous image,
+//! and the termination record (S7-S9) provides the entry point.
+
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+
+/// Maximum size of a flattened image, limited by the RCX's 16-bit
+/// address space
+const MAX_IMAGE_LEN: usize = 0x1_0000;
+
+/// The RCX ROM only checksums firmware below this address
+const CHECKSUM_END: usize = 0xcc00;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    /// Address of the first byte of `data`
+    pub start: u32,
+    /// Address execution starts from, taken from the termination record
+    pub entry: u32,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut records = BTreeMap::new();
+        let mut entry = None;
+
+        for line in input.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = Record::parse(line)?;
+            match record.ty {
+                1..=3 if records.contains_key(&record.address) => {
+                    return Err(Error::Parse("Repeated S-record address"));
+                }
+                1..=3 => {
+                    records.insert(record.address, record.data);
+                }
+                7..=9 => entry = Some(record.address),
+                // header, count and reserved records carry no image data
+                _ => {}
+            }
+        }
+
+        let Some((&start, _)) = records.first_key_value() else {
+            return Err(Error::Parse("S-record file contains no data"));
+        };
+        let entry = entry.unwrap_or(start);
+
+        let mut data = Vec::new();
+        for (address, chunk) in records {
+            let offset = (address - start) as usize;
+            if offset < data.len() {
+                return Err(Error::Parse("Overlapping S-records"));
+            }
+            // checked before the gap is filled, which a sparse address
+            // could otherwise make huge
+            if offset + chunk.len() > MAX_IMAGE_LEN {
+                return Err(Error::Parse("S-record image is too large"));
+            }
+            // gaps between records are zero-filled
+            data.resize(offset, 0);
+            data.extend(chunk);
+        }
+
+        Ok(Self { start, entry, data })
+    }
+
+    /// Firmware checksum as expected by `StartFirmwareDownload`: the sum
+    /// of all image bytes below 0xcc00, modulo 2^16
+    pub fn checksum(&self) -> u16 {
+        let len = CHECKSUM_END
+            .saturating_sub(self.start as usize)
+            .min(self.data.len());
+        self.data[..len]
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte.into()))
+    }
+}
+
+struct Record {
+    ty: u8,
+    address: u32,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn parse(line: &str) -> Result<Self> {
+        let Some(line) = line.strip_prefix('S') else {
+            return Err(Error::Parse("S-record does not start with 'S'"));
+        };
+        let mut chars = line.chars();
+        let ty = chars
+            .next()
+            .and_then(|ty| ty.to_digit(10))
+            .ok_or(Error::Parse("Invalid S-record type"))?
+            as u8;
+        let bytes = hex::decode(chars.as_str())
+            .map_err(|_| Error::Parse("Invalid hex in S-record"))?;
+
+        let (&count, rest) = bytes
+            .split_first()
+            .ok_or(Error::Parse("Truncated S-record"))?;
+        if usize::from(count) != rest.len() {
+            return Err(Error::Parse("S-record length mismatch"));
+        }
+        let sum = bytes[..bytes.len() - 1]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(Error::Checksum);
+        }
+
+        let address_len = match ty {
+            0 | 1 | 5 | 9 => 2,
+            2 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(Error::Parse("Invalid S-record type")),
+        };
+        if rest.len() < address_len + 1 {
+            return Err(Error::Parse("Truncated S-record"));
+        }
+        let address = rest[..address_len]
+            .iter()
+            .fold(0u32, |address, &byte| (address << 8) | u32::from(byte));
+        let data = rest[address_len..rest.len() - 1].to_vec();
+
+        Ok(Self { ty, address, data })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE: &str = "S00600004844521B
+S1078000010203046E
+S1058008050667
+S90380007C
+";
+
+    #[test]
+    fn parse_image() {
+        let image = Image::parse(SAMPLE).unwrap();
+        assert_eq!(
+            image,
+            Image {
+                start: 0x8000,
+                entry: 0x8000,
+                data: vec![1, 2, 3, 4, 0, 0, 0, 0, 5, 6],
+            }
+        );
+        assert_eq!(image.checksum(), 21);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let err = Image::parse("S1078000010203046F").unwrap_err();
+        assert!(matches!(err, Error::Checksum));
+    }
+
+    #[test]
+    fn sparse_image() {
+        let err =
+            Image::parse("S30700008000010275\nS3067FFF00000378").unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+    }
+
+    #[test]
+    fn checksum_stops_at_0xcc00() {
+        let image = Image {
+            start: 0x8000,
+            entry: 0x8000,
+            data: vec![1; 0x5000],
+        };
+        assert_eq!(image.checksum(), 0x4c00);
+    }
+}
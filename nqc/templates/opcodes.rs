@@ -10,6 +10,16 @@ pub struct {{ opcode.name }} {
 }
 
 impl Opcode for {{ opcode.name }} {
+    {% if let Some(response) = opcode.response %}
+    {% if response.params.is_empty() %}
+    type Response = ();
+    {% else %}
+    type Response = {{ opcode.name }}Response;
+    {% endif %}
+    {% else %}
+    type Response = ();
+    {% endif %}
+
     fn request_opcode(&self) -> u8 {
         {{ opcode.request.opcode|hex }}
     }
@@ -22,6 +32,26 @@ impl Opcode for {{ opcode.name }} {
     {% endif %}
     }
 
+    #[allow(unused_variables)]
+    fn parse_response(reply: &[u8]) -> Result<Self::Response> {
+    {% if let Some(response) = opcode.response %}
+    {% if response.params.is_empty() %}
+        {{ opcode.name }}Response::deserialise(reply)?;
+        Ok(())
+    {% else %}
+        {{ opcode.name }}Response::deserialise(reply)
+    {% endif %}
+    {% else %}
+        Ok(())
+    {% endif %}
+    }
+
+    {% if !opcode.request.supports_alternate %}
+    fn supports_alternate(&self) -> bool {
+        false
+    }
+    {% endif %}
+
     fn serialise(&self, buf: &mut [u8]) -> Result<usize> {
         #[allow(unused_mut)]
         let mut cursor = Cursor::new(buf);
@@ -60,45 +90,41 @@ pub struct {{ opcode.name }}Response {
 }
 
 impl {{ opcode.name }}Response {
+    /// Length of the reply's payload, if it is fixed
+    const LEN: Option<usize> = {
+        #[allow(unused_mut)]
+        let mut len = Some(0);
+        {% for param in response.params %}
+        len = add_len(len, <{{ param.ty }} as ReadParam>::LEN);
+        {% endfor %}
+        len
+    };
+
     pub fn deserialise(buf: &[u8]) -> Result<Self> {
-        let mut cursor = Cursor::new(buf);
+        Self::from_packet(&Packet::decode(buf)?)
+    }
 
-        // skip header bytes
-        loop {
-            let mut byte=[0];
-            cursor.read_exact(&mut byte)?;
-            if !is_header(byte[0]) {
-                cursor.seek(SeekFrom::Current(-1))?;
-                break;
-            }
+    pub fn from_packet(packet: &Packet) -> Result<Self> {
+        // the reply opcode depends on whether the request used the
+        // alternate opcode, so ignore that bit when verifying it
+        if packet.opcode | 0x08 != {{ response.opcode|hex }} | 0x08 {
+            return Err(Error::InvalidOpcode(packet.opcode));
         }
 
-        // read & verify opcode
-        let opcode = u8::read_param(&mut cursor)?;
-
-        // not every opcode will write to the checksum
-        #[allow(unused_mut)]
-        let mut checksum = opcode;
+        #[allow(unused_mut, unused_variables)]
+        let mut cursor = Cursor::new(&packet.payload);
 
         // parse out fields
         {% for param in response.params %}
         let {{ param.name }} =
             <{{ param.ty }} as ReadParam>::read_param(&mut cursor)?;
-        {{ param.name }}.add_to_checksum(&mut checksum);
         {% endfor %}
 
-        // validate checksum
-        let pkt_checksum = u8::read_param(&mut cursor)?;
-
-        if checksum == pkt_checksum {
-            Ok(Self {
-                {% for param in response.params %}
-                {{ param.name }},
-                {% endfor %}
-            })
-        } else {
-            Err(Error::Checksum)
-        }
+        Ok(Self {
+            {% for param in response.params %}
+            {{ param.name }},
+            {% endfor %}
+        })
     }
 }
 {% endif %}
@@ -115,6 +141,19 @@ impl Display for {{ opcode.name }} {
 
 {% endfor %}
 
+/// Length of the payload of a reply with `opcode`, in either form, if it
+/// is fixed
+pub(crate) fn reply_len(opcode: u8) -> Option<usize> {
+    {% for opcode in opcodes %}
+    {% if let Some(response) = opcode.response %}
+    if opcode | 0x08 == {{ response.opcode|hex }} | 0x08 {
+        return {{ opcode.name }}Response::LEN;
+    }
+    {% endif %}
+    {% endfor %}
+    None
+}
+
 pub fn parse_opcode(bin: &[u8], pc: &mut usize) -> Result<Opcodes> {
     let code = read_byte(bin, pc)?;
     match code {
@@ -126,6 +165,29 @@ pub fn parse_opcode(bin: &[u8], pc: &mut usize) -> Result<Opcodes> {
     }
 }
 
+/// Build the opcode named by an assembler mnemonic, the snake_case form
+/// of its name, from its operands in the order of its parameters
+pub fn assemble_opcode(mnemonic: &str, args: &[i32]) -> Result<Opcodes> {
+    #[allow(unused_mut)]
+    let mut args = args.iter().copied();
+    let opcode = match mnemonic {
+        {% for opcode in opcodes %}
+        "{{ opcode.name|snake_case }}" =>
+            Opcodes::{{ opcode.name }}({{ opcode.name }} {
+                {% for param in opcode.request.params %}
+                {{ param.name }}:
+                    <{{ param.ty }} as AsmParam>::asm_param(&mut args)?,
+                {% endfor %}
+            }),
+        {% endfor %}
+        _ => return Err(Error::Parse("Unknown mnemonic")),
+    };
+    if args.next().is_some() {
+        return Err(Error::Parse("Too many arguments"));
+    }
+    Ok(opcode)
+}
+
 #[derive(Debug)]
 pub enum Opcodes {
     {% for opcode in opcodes %}
@@ -144,6 +206,9 @@ impl Display for Opcodes {
 }
 
 impl Opcode for Opcodes {
+    /// The reply packet, since the request is only known at runtime
+    type Response = Option<Packet>;
+
     fn request_opcode(&self) -> u8 {
         match self {
             {% for opcode in opcodes %}
@@ -152,7 +217,18 @@ impl Opcode for Opcodes {
         }
     }
     fn response_opcode(&self) -> Option<u8> {
-        todo!()
+        match self {
+            {% for opcode in opcodes %}
+            Self::{{ opcode.name }}(code) => code.response_opcode(),
+            {% endfor %}
+        }
+    }
+    fn supports_alternate(&self) -> bool {
+        match self {
+            {% for opcode in opcodes %}
+            Self::{{ opcode.name }}(code) => code.supports_alternate(),
+            {% endfor %}
+        }
     }
     fn serialise(&self, buf: &mut [u8]) -> Result<usize> {
         match self {
@@ -161,7 +237,14 @@ impl Opcode for Opcodes {
             {% endfor %}
         }
     }
-    fn disasm(_bin: &[u8], _pc: &mut usize) -> Result<Self> {
-        todo!()
+    fn parse_response(reply: &[u8]) -> Result<Self::Response> {
+        if reply.is_empty() {
+            Ok(None)
+        } else {
+            Packet::decode(reply).map(Some)
+        }
+    }
+    fn disasm(bin: &[u8], pc: &mut usize) -> Result<Self> {
+        parse_opcode(bin, pc)
     }
 }
\ No newline at end of file
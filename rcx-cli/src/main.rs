@@ -0,0 +1,616 @@
+//! Command-line interface for talking to an RCX brick through an IR
+//! tower.
+//!
+//! Every command prints a human-readable summary, or a single JSON
+//! document with `--json` for use from scripts.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{bail, eyre, Result};
+use nqc::{binfmt::RcxBin, srec};
+use rcx::{
+    datalog, memory,
+    tower::{record::RecordingTower, tcp, IrTower, TowerConfig},
+    MotorDirection, MotorPowerState, MotorSelection, Rcx, TransmitterRange,
+};
+use serde_json::{json, Value};
+use std::{net::TcpListener, path::PathBuf, time::Duration};
+
+/// Port used by `serve` and the TCP transport if none is given
+const DEFAULT_PORT: u16 = 50637;
+
+#[derive(Parser)]
+#[command(version, about = "Communicate with LEGO RCX bricks")]
+struct Cli {
+    /// Tower transport to use
+    #[arg(short, long, value_enum, default_value_t = Transport::Usb)]
+    transport: Transport,
+
+    /// Path to the tower device, or host:port of a tower server for the
+    /// TCP transport. Defaults to /dev/usb/legousbtower0 for the USB
+    /// tower, /dev/ttyS0 for the serial tower and localhost:50637 for
+    /// TCP.
+    #[arg(short, long)]
+    device: Option<String>,
+
+    /// How long to wait for each reply, in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    timeout: u64,
+
+    /// Number of times to send each request before giving up
+    #[arg(long, default_value_t = 3)]
+    attempts: usize,
+
+    /// Use the long range transmitter setting
+    #[arg(long)]
+    long_range: bool,
+
+    /// Log all tower traffic to this file, for replaying later
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Print output as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Transport {
+    Usb,
+    Serial,
+    /// A tower shared by `rcx serve` on another machine
+    Tcp,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check whether the brick is responding
+    Alive,
+    /// Show the battery voltage
+    Battery,
+    /// Show the ROM and firmware versions
+    Versions,
+    /// Show how much memory each program slot and the datalog use
+    Memory,
+    /// Download a compiled `.rcx` program
+    Download {
+        file: PathBuf,
+        /// Program slot, 1-5
+        #[arg(short, long, default_value_t = 1)]
+        slot: u8,
+    },
+    /// Start a task, by default task 0 of the current program
+    Run {
+        /// Program slot to select first, 1-5
+        #[arg(short, long)]
+        slot: Option<u8>,
+        #[arg(long, default_value_t = 0)]
+        task: u8,
+    },
+    /// Stop all running tasks
+    Stop,
+    /// Replace the firmware with an S-record image, e.g. `firm0332.lgo`
+    Firmware { file: PathBuf },
+    /// Upload the datalog
+    Datalog {
+        /// Print the log as CSV
+        #[arg(long, conflicts_with = "json")]
+        csv: bool,
+    },
+    /// Set the brick's clock
+    SetTime {
+        /// Time of day as HH:MM
+        #[arg(value_parser = parse_time)]
+        time: (u8, u8),
+    },
+    /// Control motors, or show their state if no changes are given
+    Motor {
+        /// Motors to address, e.g. `A` or `AC`
+        #[arg(value_parser = parse_motors)]
+        motors: MotorSelection,
+        /// Power level, 0-7
+        #[arg(short, long)]
+        power: Option<u8>,
+        #[arg(short, long, value_enum)]
+        direction: Option<Direction>,
+        #[arg(short, long, value_enum)]
+        state: Option<PowerState>,
+    },
+    /// Read a sensor, optionally configuring it first
+    Sensor {
+        /// Sensor number, 1-3
+        sensor: u8,
+        #[arg(short, long, value_enum)]
+        r#type: Option<SensorType>,
+        #[arg(short, long, value_enum)]
+        mode: Option<SensorMode>,
+    },
+    /// Play one of the built-in sounds
+    Sound {
+        #[arg(value_enum)]
+        sound: Sound,
+    },
+    /// Share the tower with other machines over TCP
+    ///
+    /// There is no authentication: anyone who can connect can send any
+    /// request to the brick, including downloading firmware and deleting
+    /// programs. Only listen on other interfaces on a trusted network.
+    Serve {
+        /// Address to listen on. Only this machine can connect by
+        /// default; use e.g. 0.0.0.0:50637 to accept other machines
+        #[arg(short, long, default_value_t = format!("127.0.0.1:{DEFAULT_PORT}"))]
+        listen: String,
+    },
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+impl From<Direction> for MotorDirection {
+    fn from(value: Direction) -> Self {
+        match value {
+            Direction::Forward => Self::Forward,
+            Direction::Reverse => Self::Reverse,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum PowerState {
+    On,
+    Off,
+    Float,
+}
+
+impl From<PowerState> for MotorPowerState {
+    fn from(value: PowerState) -> Self {
+        match value {
+            PowerState::On => Self::On,
+            PowerState::Off => Self::Off,
+            PowerState::Float => Self::Float,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum SensorType {
+    Raw,
+    Touch,
+    Temperature,
+    Light,
+    Rotation,
+}
+
+impl From<SensorType> for rcx::SensorType {
+    fn from(value: SensorType) -> Self {
+        match value {
+            SensorType::Raw => Self::Raw,
+            SensorType::Touch => Self::Touch,
+            SensorType::Temperature => Self::Temperature,
+            SensorType::Light => Self::Light,
+            SensorType::Rotation => Self::Rotation,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum SensorMode {
+    Raw,
+    Boolean,
+    EdgeCount,
+    PulseCount,
+    Percentage,
+    Celsius,
+    Fahrenheit,
+    Angle,
+}
+
+impl From<SensorMode> for rcx::SensorMode {
+    fn from(value: SensorMode) -> Self {
+        match value {
+            SensorMode::Raw => Self::Raw,
+            SensorMode::Boolean => Self::Boolean,
+            SensorMode::EdgeCount => Self::EdgeCount,
+            SensorMode::PulseCount => Self::PulseCount,
+            SensorMode::Percentage => Self::Percentage,
+            SensorMode::Celsius => Self::TemperatureC,
+            SensorMode::Fahrenheit => Self::TemperatureF,
+            SensorMode::Angle => Self::Angle,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Sound {
+    Blip,
+    BeepBeep,
+    DownwardTones,
+    UpwardTones,
+    LowBuzz,
+    FastUpwardTones,
+}
+
+impl From<Sound> for rcx::Sound {
+    fn from(value: Sound) -> Self {
+        match value {
+            Sound::Blip => Self::Blip,
+            Sound::BeepBeep => Self::BeepBeep,
+            Sound::DownwardTones => Self::DownwardTones,
+            Sound::UpwardTones => Self::UpwardTones,
+            Sound::LowBuzz => Self::LowBuzz,
+            Sound::FastUpwardTones => Self::FastUpwardTones,
+        }
+    }
+}
+
+/// Result of a command, in both output formats
+struct Output {
+    json: Value,
+    text: String,
+}
+
+impl Output {
+    fn new(json: Value, text: impl Into<String>) -> Self {
+        Self {
+            json,
+            text: text.into(),
+        }
+    }
+
+    fn ok(text: impl Into<String>) -> Self {
+        Self::new(json!({ "ok": true }), text)
+    }
+}
+
+fn parse_time(input: &str) -> Result<(u8, u8), String> {
+    let err = || format!("invalid time `{input}`, expected HH:MM");
+    let (hours, minutes) = input.split_once(':').ok_or_else(err)?;
+    let hours = hours.parse::<u8>().map_err(|_| err())?;
+    let minutes = minutes.parse::<u8>().map_err(|_| err())?;
+    if hours > 23 || minutes > 59 {
+        return Err(err());
+    }
+    Ok((hours, minutes))
+}
+
+fn parse_motors(input: &str) -> Result<MotorSelection, String> {
+    let mut motors = MotorSelection { bitfield: 0 };
+    for motor in input.chars() {
+        motors = motors
+            | match motor.to_ascii_uppercase() {
+                'A' => MotorSelection::A,
+                'B' => MotorSelection::B,
+                'C' => MotorSelection::C,
+                _ => return Err(format!("invalid motor `{motor}`")),
+            };
+    }
+    if motors.bitfield == 0 {
+        return Err("no motors given".into());
+    }
+    Ok(motors)
+}
+
+fn motor_name(motor: MotorSelection) -> char {
+    match motor {
+        MotorSelection::A => 'A',
+        MotorSelection::B => 'B',
+        _ => 'C',
+    }
+}
+
+/// Convert a user-facing 1-based number to the 0-based index used on
+/// the wire
+fn zero_based(value: u8, max: u8, what: &str) -> Result<u8> {
+    if !(1..=max).contains(&value) {
+        bail!("{what} must be 1-{max}");
+    }
+    Ok(value - 1)
+}
+
+fn open(cli: &Cli) -> Result<Box<dyn IrTower>> {
+    let mut config = TowerConfig::new()
+        .read_timeout(Duration::from_millis(cli.timeout))
+        .attempts(cli.attempts);
+    if cli.long_range {
+        config = config.range(TransmitterRange::Long);
+    }
+
+    match cli.transport {
+        #[cfg(feature = "usbtower")]
+        Transport::Usb => {
+            let device = cli
+                .device
+                .clone()
+                .unwrap_or_else(|| "/dev/usb/legousbtower0".into());
+            let tower =
+                rcx::tower::usb::UsbTower::open_with_config(device, config)?;
+            Ok(Box::new(tower))
+        }
+        #[cfg(feature = "serialtower")]
+        Transport::Serial => {
+            let device =
+                cli.device.clone().unwrap_or_else(|| "/dev/ttyS0".into());
+            let tower = rcx::tower::serial::SerialTower::open_with_config(
+                device, config,
+            )?;
+            Ok(Box::new(tower))
+        }
+        Transport::Tcp => {
+            let addr = cli
+                .device
+                .clone()
+                .unwrap_or_else(|| format!("localhost:{DEFAULT_PORT}"));
+            Ok(Box::new(tcp::TcpTower::connect_with_config(addr, config)?))
+        }
+        #[allow(unreachable_patterns)]
+        transport => Err(eyre!(
+            "support for the {transport:?} tower was not compiled in"
+        )),
+    }
+}
+
+fn run(cli: &Cli, rcx: &mut Rcx) -> Result<Output> {
+    Ok(match &cli.command {
+        Command::Alive => {
+            rcx.alive()?;
+            Output::new(json!({ "alive": true }), "RCX is alive")
+        }
+        Command::Battery => {
+            let millivolts = rcx.get_battery_power()?.millivolts;
+            Output::new(
+                json!({ "millivolts": millivolts }),
+                format!("Battery: {:.2} V", f64::from(millivolts) / 1000.0),
+            )
+        }
+        Command::Versions => {
+            let resp = rcx.get_versions()?;
+            Output::new(
+                json!({ "rom": resp.rom, "firmware": resp.firmware }),
+                format!(
+                    "ROM: {:04x}.{:04x}\nFirmware: {:04x}.{:04x}",
+                    resp.rom[0],
+                    resp.rom[1],
+                    resp.firmware[0],
+                    resp.firmware[1],
+                ),
+            )
+        }
+        Command::Memory => {
+            let map = rcx.get_memory_map()?;
+            let programs = (0..memory::PROGRAM_SLOTS)
+                .map(|slot| map.program_size(slot))
+                .collect::<Vec<_>>();
+            let mut text = programs
+                .iter()
+                .enumerate()
+                .map(|(slot, size)| {
+                    format!("Program {}: {size} bytes", slot + 1)
+                })
+                .collect::<Vec<_>>();
+            text.push(format!("Datalog: {} bytes", map.datalog_size()));
+            text.push(format!("Free: {} bytes", map.free()));
+            Output::new(
+                json!({
+                    "programs": programs,
+                    "datalog": map.datalog_size(),
+                    "free": map.free(),
+                }),
+                text.join("\n"),
+            )
+        }
+        Command::Download { file, slot } => {
+            let slot = zero_based(*slot, 5, "Program slot")?;
+            let bin = RcxBin::parse(&std::fs::read(file)?)?;
+            if !rcx.get_memory_map()?.fits(slot.into(), &bin) {
+                bail!("Program does not fit into the brick's free memory");
+            }
+            rcx.download_program(slot, &bin)?;
+            Output::ok(format!(
+                "Downloaded {} sections to slot {}",
+                bin.sections.len(),
+                slot + 1,
+            ))
+        }
+        Command::Run { slot, task } => {
+            if let Some(slot) = slot {
+                rcx.set_program_number(zero_based(*slot, 5, "Program slot")?)?;
+            }
+            rcx.start_task(*task)?;
+            Output::ok(format!("Started task {task}"))
+        }
+        Command::Stop => {
+            rcx.stop_all_tasks()?;
+            Output::ok("Stopped all tasks")
+        }
+        Command::Firmware { file } => {
+            let image = srec::Image::parse(&std::fs::read_to_string(file)?)?;
+            let quiet = cli.json;
+            rcx.download_firmware(&image, |sent, total| {
+                if !quiet {
+                    eprint!("\rDownloading firmware: {sent}/{total} bytes");
+                }
+            })?;
+            if !quiet {
+                eprintln!();
+            }
+            Output::ok(format!(
+                "Downloaded {} bytes of firmware",
+                image.data.len()
+            ))
+        }
+        Command::Datalog { csv } => {
+            let entries = rcx.upload_datalog()?;
+            let mut text = Vec::new();
+            if *csv {
+                datalog::write_csv(&mut text, &entries)?;
+            } else {
+                for entry in &entries {
+                    use std::io::Write;
+                    writeln!(text, "{}: {}", entry.source, entry.value)?;
+                }
+            }
+            let json = entries
+                .iter()
+                .map(|entry| {
+                    json!({
+                        "source": entry.source.name(),
+                        "index": entry.source.index(),
+                        "value": entry.value,
+                    })
+                })
+                .collect();
+            Output::new(
+                json,
+                String::from_utf8_lossy(&text).trim_end().to_string(),
+            )
+        }
+        Command::SetTime {
+            time: (hours, minutes),
+        } => {
+            rcx.set_time(*hours, *minutes)?;
+            Output::ok(format!("Clock set to {hours:02}:{minutes:02}"))
+        }
+        Command::Motor {
+            motors,
+            power,
+            direction,
+            state,
+        } => {
+            if power.is_none() && direction.is_none() && state.is_none() {
+                return motor_states(rcx, *motors);
+            }
+            if let Some(power) = power {
+                rcx.set_motor_power(*motors, *power)?;
+            }
+            if let Some(direction) = direction {
+                rcx.set_motor_direction(*motors, (*direction).into())?;
+            }
+            if let Some(state) = state {
+                rcx.set_motor_on_off(*motors, (*state).into())?;
+            }
+            Output::ok("Motors updated")
+        }
+        Command::Sensor {
+            sensor,
+            r#type,
+            mode,
+        } => {
+            let index = zero_based(*sensor, 3, "Sensor")?;
+            if let Some(ty) = r#type {
+                rcx.set_sensor_type(index, (*ty).into())?;
+            }
+            if let Some(mode) = mode {
+                rcx.set_sensor_mode(index, (*mode).into())?;
+            }
+            let value = rcx.read_sensor(index)?;
+            let raw = rcx.read_raw_sensor(index)?;
+            Output::new(
+                json!({ "sensor": sensor, "value": value, "raw": raw }),
+                format!("Sensor {sensor}: {value} (raw {raw})"),
+            )
+        }
+        Command::Sound { sound } => {
+            rcx.play_sound((*sound).into())?;
+            Output::ok("")
+        }
+        Command::Serve { .. } => unreachable!("handled in main"),
+    })
+}
+
+fn motor_states(rcx: &mut Rcx, motors: MotorSelection) -> Result<Output> {
+    let mut json = serde_json::Map::new();
+    let mut text = Vec::new();
+    for motor in [MotorSelection::A, MotorSelection::B, MotorSelection::C] {
+        if motors.bitfield & motor.bitfield == 0 {
+            continue;
+        }
+        let name = motor_name(motor);
+        let state = rcx.read_motor_state(motor)?;
+        json.insert(
+            name.to_string(),
+            json!({
+                "power": state.power,
+                "direction": format!("{:?}", state.direction),
+                "state": format!("{:?}", state.state),
+            }),
+        );
+        text.push(format!(
+            "Motor {name}: {:?}, {:?}, power {}",
+            state.state, state.direction, state.power
+        ));
+    }
+    Ok(Output::new(json.into(), text.join("\n")))
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    let cli = Cli::parse();
+
+    let mut tower = open(&cli)?;
+    if let Some(path) = &cli.record {
+        tower = Box::new(RecordingTower::create(tower, path)?);
+    }
+    if let Command::Serve { listen } = &cli.command {
+        let listener = TcpListener::bind(listen)?;
+        let addr = listener.local_addr()?;
+        eprintln!("Serving tower on {addr}");
+        if !addr.ip().is_loopback() {
+            eprintln!(
+                "Warning: there is no authentication, any machine which \
+                 can reach {addr} can control the tower"
+            );
+        }
+        tcp::serve(listener, &mut *tower)?;
+        return Ok(());
+    }
+
+    let mut rcx = Rcx::new(tower);
+    let output = run(&cli, &mut rcx)?;
+    if cli.json {
+        println!("{}", output.json);
+    } else if !output.text.is_empty() {
+        println!("{}", output.text);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn time() {
+        assert_eq!(parse_time("09:45"), Ok((9, 45)));
+        assert!(parse_time("24:00").is_err());
+        assert!(parse_time("0945").is_err());
+    }
+
+    #[test]
+    fn motors() {
+        assert!(
+            parse_motors("ac") == Ok(MotorSelection::A | MotorSelection::C)
+        );
+        assert!(parse_motors("").is_err());
+        assert!(parse_motors("AD").is_err());
+    }
+
+    #[test]
+    fn serve_listens_locally() {
+        let cli = Cli::parse_from(["rcx", "serve"]);
+        assert!(matches!(
+            cli.command,
+            Command::Serve { listen } if listen == "127.0.0.1:50637"
+        ));
+    }
+}
@@ -0,0 +1,523 @@
+//! Async counterpart of [`Rcx`](crate::Rcx), available with the `tokio`
+//! feature.
+//!
+//! ```no_run
+//! use rcx::{tower::usb::AsyncUsbTower, AsyncRcx};
+//!
+//! # async fn example() -> rcx::Result<()> {
+//! let tower = AsyncUsbTower::open("/dev/usb/legousbtower0")?;
+//! let mut rcx = AsyncRcx::new(tower);
+//! let battery = rcx.get_battery_power().await?;
+//! println!("{} mV", battery.millivolts);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    datalog::DatalogEntry,
+    memory, message, opcodes,
+    requests::{self, check_download},
+    tower::AsyncIrTower,
+    MotorDirection, MotorPowerState, MotorSelection, MotorState, RemoteButtons,
+    Result, SensorMode, SensorType, Sound, SourceType, TransmitterRange,
+};
+use nqc::{binfmt::RcxBin, srec};
+
+/// Async client for an RCX brick. Mirrors the [`Rcx`](crate::Rcx) API,
+/// with every method returning a future.
+///
+/// Unlike `Rcx`, the tower is a type parameter as [`AsyncIrTower`]
+/// cannot be used as a trait object.
+pub struct AsyncRcx<T> {
+    tower: T,
+    /// Transmitter range from the tower config which has not been sent
+    /// to the brick yet
+    pending_range: Option<TransmitterRange>,
+}
+
+impl<T: AsyncIrTower> AsyncRcx<T> {
+    pub fn new(tower: T) -> Self {
+        Self {
+            pending_range: tower.config().range,
+            tower,
+        }
+    }
+
+    /// Send a request through the tower, first selecting the configured
+    /// transmitter range if that has not been done yet
+    async fn send_recv(
+        &mut self,
+        msg: &(dyn opcodes::Opcode + Sync),
+    ) -> Result<Vec<u8>> {
+        if let Some(range) = self.pending_range {
+            self.tower
+                .send_recv(&opcodes::SetTransmitterRange { range: range as u8 })
+                .await?;
+            self.pending_range = None;
+        }
+        self.tower.send_recv(msg).await
+    }
+
+    pub async fn alive(&mut self) -> Result<()> {
+        self.execute(&opcodes::Alive {}).await
+    }
+
+    /// Add an entry to the datalog with the value of a variable, timer,
+    /// sensor or the clock
+    pub async fn datalog_next(
+        &mut self,
+        source: SourceType,
+        argument: u8,
+    ) -> Result<()> {
+        let resp = self
+            .execute(&requests::datalog_next(source, argument)?)
+            .await?;
+        requests::check_datalog_next(resp.errorcode)
+    }
+
+    pub async fn delete_all_subroutines(&mut self) -> Result<()> {
+        self.execute(&opcodes::DeleteAllSubroutines {}).await
+    }
+
+    pub async fn delete_all_tasks(&mut self) -> Result<()> {
+        self.execute(&opcodes::DeleteAllTasks {}).await
+    }
+
+    pub async fn delete_firmware(&mut self) -> Result<()> {
+        self.execute(&requests::delete_firmware()).await
+    }
+
+    pub async fn delete_subroutine(&mut self, subroutine: u8) -> Result<()> {
+        self.execute(&requests::delete_subroutine(subroutine)?)
+            .await
+    }
+
+    pub async fn delete_task(&mut self, task: u8) -> Result<()> {
+        self.execute(&requests::delete_task(task)?).await
+    }
+
+    /// Download a parsed `.rcx` image into the given program slot (0-4).
+    ///
+    /// Every section is checked before anything is sent, then all
+    /// existing tasks and subroutines in the slot are deleted. If a
+    /// section is rejected or fails to download, the returned
+    /// [`Error::SectionDownload`](crate::Error::SectionDownload) identifies which one.
+    pub async fn download_program(
+        &mut self,
+        slot: u8,
+        bin: &RcxBin,
+    ) -> Result<()> {
+        let program = requests::set_program_number(slot)?;
+        let sections = requests::plan_program(bin, &requests::RCX_LIMITS)?;
+        self.execute(&program).await?;
+        self.delete_all_tasks().await?;
+        self.delete_all_subroutines().await?;
+
+        for plan in &sections {
+            self.download_section(plan).await.map_err(|source| {
+                requests::section_error(plan.section, source)
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn download_section(
+        &mut self,
+        plan: &requests::SectionPlan<'_>,
+    ) -> Result<()> {
+        let errorcode = match &plan.start {
+            requests::SectionDownload::Task(msg) => {
+                self.execute(msg).await?.errorcode
+            }
+            requests::SectionDownload::Subroutine(msg) => {
+                self.execute(msg).await?.errorcode
+            }
+        };
+        check_download(errorcode)?;
+        for block in &plan.blocks {
+            check_download(self.execute(block).await?.errorcode)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the firmware on the brick with the given S-record image,
+    /// e.g. one parsed from `firm0332.lgo`.
+    ///
+    /// `progress` is called after every acknowledged block with the
+    /// number of bytes sent so far and the total image size. A full
+    /// download takes several minutes.
+    pub async fn download_firmware(
+        &mut self,
+        image: &srec::Image,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        let (start, mut download) = requests::FirmwareDownload::new(image)?;
+
+        self.delete_firmware().await?;
+        check_download(self.execute(&start).await?.errorcode)?;
+
+        while let Some(block) = download.next_block() {
+            let result = self
+                .execute(&block?)
+                .await
+                .and_then(|resp| check_download(resp.errorcode));
+            if let Some((sent, total)) = download.complete(result)? {
+                progress(sent, total);
+            }
+        }
+
+        self.unlock_firmware().await
+    }
+
+    /// Send any request and parse its reply, as [`crate::Rcx::execute`]
+    pub async fn execute<O: opcodes::Opcode + Sync>(
+        &mut self,
+        msg: &O,
+    ) -> Result<O::Response> {
+        let reply = self.send_recv(msg).await?;
+        O::parse_response(&reply)
+    }
+
+    pub async fn get_battery_power(
+        &mut self,
+    ) -> Result<opcodes::GetBatteryPowerResponse> {
+        self.execute(&opcodes::GetBatteryPower {}).await
+    }
+
+    /// Fetch the memory map, giving where each task and subroutine is
+    /// stored and how much memory remains
+    pub async fn get_memory_map(&mut self) -> Result<memory::MemoryMap> {
+        Ok(self.execute(&opcodes::GetMemoryMap {}).await?.into())
+    }
+
+    pub async fn get_value(
+        &mut self,
+        source: SourceType,
+        argument: u8,
+    ) -> Result<opcodes::GetValueResponse> {
+        self.execute(&requests::get_value(source, argument)?).await
+    }
+
+    pub async fn get_versions(
+        &mut self,
+    ) -> Result<opcodes::GetVersionsResponse> {
+        self.execute(&requests::get_versions()).await
+    }
+
+    /// Listen for messages broadcast by other bricks. Send messages to
+    /// them with [`Self::set_message`].
+    pub fn messages(&mut self) -> message::AsyncMessages<'_, T> {
+        message::AsyncMessages::new(&mut self.tower)
+    }
+
+    pub async fn play_sound(&mut self, sound: Sound) -> Result<()> {
+        self.execute(&opcodes::PlaySound { sound }).await
+    }
+
+    pub async fn play_tone(
+        &mut self,
+        frequency_hz: i16,
+        duration_cs: i8,
+    ) -> Result<()> {
+        self.execute(&opcodes::PlayTone {
+            frequency: frequency_hz,
+            duration: duration_cs,
+        })
+        .await
+    }
+
+    pub async fn power_off(&mut self) -> Result<()> {
+        self.execute(&opcodes::PowerOff {}).await
+    }
+
+    /// Minutes since the brick was powered on
+    pub async fn read_clock(&mut self) -> Result<i16> {
+        Ok(self.get_value(SourceType::Clock, 0).await?.value)
+    }
+
+    /// Contents of the message buffer
+    pub async fn read_message(&mut self) -> Result<u8> {
+        Ok(self.get_value(SourceType::Message, 0).await?.value as u8)
+    }
+
+    pub async fn read_motor_state(
+        &mut self,
+        motor: MotorSelection,
+    ) -> Result<MotorState> {
+        let index = requests::motor_index(motor)?;
+        let value = self.get_value(SourceType::MotorState, index).await?.value;
+        MotorState::try_from(value as u8)
+    }
+
+    /// Raw value of a sensor (0-2), 0..1023
+    pub async fn read_raw_sensor(&mut self, sensor: u8) -> Result<i16> {
+        let msg = requests::read_sensor(SourceType::RawSensorValue, sensor)?;
+        Ok(self.execute(&msg).await?.value)
+    }
+
+    /// Value of a sensor (0-2), scaled according to its mode
+    pub async fn read_sensor(&mut self, sensor: u8) -> Result<i16> {
+        let msg = requests::read_sensor(SourceType::SensorValue, sensor)?;
+        Ok(self.execute(&msg).await?.value)
+    }
+
+    /// Value of a timer (0-3), in 1/100ths of a second
+    pub async fn read_timer(&mut self, timer: u8) -> Result<i16> {
+        Ok(self.execute(&requests::read_timer(timer)?).await?.value)
+    }
+
+    pub async fn read_variable(&mut self, variable: u8) -> Result<i16> {
+        Ok(self
+            .execute(&requests::read_variable(variable)?)
+            .await?
+            .value)
+    }
+
+    /// Act as the LEGO remote control with `buttons` held down. Motors
+    /// keep running until the buttons are released by sending
+    /// [`RemoteButtons::NONE`].
+    pub async fn remote(&mut self, buttons: RemoteButtons) -> Result<()> {
+        self.execute(&opcodes::Remote {
+            buttons: buttons.bitfield.to_be_bytes(),
+        })
+        .await
+    }
+
+    /// Allocate a new, empty datalog with space for `size` entries,
+    /// discarding the current one. A size of 0 frees the datalog.
+    pub async fn set_datalog_size(&mut self, size: i16) -> Result<()> {
+        let resp = self.execute(&requests::set_datalog_size(size)?).await?;
+        requests::check_set_datalog_size(resp.errorcode)
+    }
+
+    pub async fn set_display(
+        &mut self,
+        source: SourceType,
+        argument: u8,
+    ) -> Result<()> {
+        self.execute(&opcodes::SetDisplay { source, argument })
+            .await
+    }
+
+    pub async fn set_message(&mut self, message: u8) -> Result<()> {
+        self.execute(&opcodes::SetMessage { message }).await
+    }
+
+    pub async fn set_motor_direction(
+        &mut self,
+        motor: MotorSelection,
+        direction: MotorDirection,
+    ) -> Result<()> {
+        self.execute(&requests::set_motor_direction(motor, direction))
+            .await
+    }
+
+    pub async fn set_motor_on_off(
+        &mut self,
+        motor: MotorSelection,
+        state: MotorPowerState,
+    ) -> Result<()> {
+        self.execute(&requests::set_motor_on_off(motor, state))
+            .await
+    }
+
+    pub async fn set_motor_power(
+        &mut self,
+        motor: MotorSelection,
+        power: u8,
+    ) -> Result<()> {
+        self.execute(&requests::set_motor_power(motor, power)?)
+            .await
+    }
+
+    pub async fn set_power_down_delay(&mut self, minutes: u8) -> Result<()> {
+        self.execute(&opcodes::SetPowerDownDelay { minutes }).await
+    }
+
+    pub async fn set_program_number(&mut self, program: u8) -> Result<()> {
+        self.execute(&requests::set_program_number(program)?).await
+    }
+
+    pub async fn set_sensor_mode(
+        &mut self,
+        sensor: u8,
+        mode: SensorMode,
+    ) -> Result<()> {
+        self.execute(&requests::set_sensor_mode(sensor, mode)?)
+            .await
+    }
+
+    pub async fn set_sensor_type(
+        &mut self,
+        sensor: u8,
+        ty: SensorType,
+    ) -> Result<()> {
+        self.execute(&requests::set_sensor_type(sensor, ty)?).await
+    }
+
+    pub async fn set_time(&mut self, hours: u8, minutes: u8) -> Result<()> {
+        self.execute(&requests::set_time(hours, minutes)?).await
+    }
+
+    pub async fn set_transmitter_range(
+        &mut self,
+        range: TransmitterRange,
+    ) -> Result<()> {
+        self.pending_range = None;
+        self.execute(&opcodes::SetTransmitterRange { range: range as u8 })
+            .await
+    }
+
+    pub async fn start_firmware_download(
+        &mut self,
+        address: i16,
+        checksum: i16,
+    ) -> Result<()> {
+        let resp = self
+            .execute(&opcodes::StartFirmwareDownload {
+                address,
+                checksum,
+                unknown: 0,
+            })
+            .await?;
+        check_download(resp.errorcode)
+    }
+
+    /// Allocate space for a subroutine of the current program, to be
+    /// sent with [`Self::transfer_data`]. Fails with
+    /// [`Error::OutOfMemory`](crate::Error::OutOfMemory) if there is not enough space.
+    pub async fn start_subroutine_download(
+        &mut self,
+        subroutine: u8,
+        length: i16,
+    ) -> Result<()> {
+        let msg = requests::start_subroutine_download(subroutine, length)?;
+        check_download(self.execute(&msg).await?.errorcode)
+    }
+
+    pub async fn start_task(&mut self, task: u8) -> Result<()> {
+        self.execute(&requests::start_task(task)?).await
+    }
+
+    /// Allocate space for a task of the current program, to be sent
+    /// with [`Self::transfer_data`]. Fails with [`Error::OutOfMemory`](crate::Error::OutOfMemory)
+    /// if there is not enough space.
+    pub async fn start_task_download(
+        &mut self,
+        task: u8,
+        length: i16,
+    ) -> Result<()> {
+        let msg = requests::start_task_download(task, length)?;
+        check_download(self.execute(&msg).await?.errorcode)
+    }
+
+    pub async fn stop_all_tasks(&mut self) -> Result<()> {
+        self.execute(&opcodes::StopAllTasks {}).await
+    }
+
+    pub async fn stop_task(&mut self, task: u8) -> Result<()> {
+        self.execute(&requests::stop_task(task)?).await
+    }
+
+    /// Send a block of the download in progress. Fails with
+    /// [`Error::BlockChecksum`](crate::Error::BlockChecksum) if the block was corrupted on the way, in
+    /// which case it may be sent again.
+    pub async fn transfer_data(
+        &mut self,
+        index: i16,
+        length: i16,
+        data: Vec<u8>,
+        checksum: u8,
+    ) -> Result<()> {
+        let resp = self
+            .execute(&opcodes::TransferData {
+                index,
+                length,
+                data,
+                checksum,
+            })
+            .await?;
+        check_download(resp.errorcode)
+    }
+
+    pub async fn unlock_firmware(&mut self) -> Result<()> {
+        let resp = self.execute(&requests::unlock_firmware()).await?;
+        requests::check_unlock_firmware(&resp)
+    }
+
+    /// Fetch the entire datalog, in as few requests as the brick allows.
+    /// The size entry is not included in the result.
+    pub async fn upload_datalog(&mut self) -> Result<Vec<DatalogEntry>> {
+        let size =
+            requests::datalog_size(&self.upload_datalog_entries(0, 1).await?)?;
+        let mut entries = Vec::new();
+        for (first, count) in requests::datalog_chunks(size) {
+            entries.extend(self.upload_datalog_entries(first, count).await?);
+        }
+        Ok(entries)
+    }
+
+    async fn upload_datalog_entries(
+        &mut self,
+        first: i16,
+        count: i16,
+    ) -> Result<Vec<DatalogEntry>> {
+        let resp = self
+            .execute(&opcodes::UploadDatalog { first, count })
+            .await?;
+        requests::parse_datalog(&resp, count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{bin, mock, section};
+    use crate::tower::{mock::Reply, TowerConfig};
+    use nqc::binfmt::{SectionType, TargetType};
+
+    #[tokio::test]
+    async fn alive() {
+        let tower = mock()
+            .with_config(TowerConfig::new().range(TransmitterRange::Short));
+        let mut rcx = AsyncRcx::new(tower.clone());
+        rcx.alive().await.unwrap();
+        tower.assert_sent(&[
+            &opcodes::SetTransmitterRange { range: 0 },
+            &opcodes::Alive {},
+        ]);
+    }
+
+    #[tokio::test]
+    async fn get_battery_power() {
+        let tower = mock();
+        tower.push(Reply::Timeout);
+        tower.push_reply(vec![0x43, 0x1e]);
+        let mut rcx = AsyncRcx::new(tower.clone());
+        let resp = rcx.get_battery_power().await.unwrap();
+        assert_eq!(resp.millivolts, 7747);
+        assert_eq!(tower.sent().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn download_program() {
+        let tower = mock();
+        let mut rcx = AsyncRcx::new(tower.clone());
+        let bin = bin(TargetType::Rcx, vec![section(SectionType::Task, 0, 25)]);
+        rcx.download_program(1, &bin).await.unwrap();
+        assert_eq!(tower.sent().len(), 6);
+        tower.assert_last_sent(&opcodes::TransferData {
+            index: 0,
+            length: 5,
+            data: (20..25).collect(),
+            checksum: (20..25).sum(),
+        });
+    }
+
+    #[tokio::test]
+    async fn futures_are_send() {
+        fn assert_send<F: std::future::Future + Send>(_: F) {}
+        let mut rcx = AsyncRcx::new(mock());
+        assert_send(rcx.get_versions());
+    }
+}
@@ -0,0 +1,345 @@
+//! Client for the LEGO CyberMaster, the brick of the Technic
+//! CyberMaster set.
+//!
+//! The CyberMaster talks to the PC through an RF tower rather than IR,
+//! using RCX packets behind a different header, so its tower must be
+//! opened with [`Framing::CyberMaster`]. It ignores most requests until
+//! it has been unlocked, which [`CyberMaster::connect`] does.
+//!
+//! It has two built-in drive motors with tachometers (A and B), one
+//! external motor port (C), three touch sensor inputs, four tasks and a
+//! single program.
+
+use crate::{
+    opcodes::{self, Opcode},
+    requests,
+    tower::IrTower,
+    Error, MotorDirection, MotorPowerState, MotorSelection, Rcx, Result, Sound,
+    SourceType, TransmitterRange,
+};
+use nqc::{
+    binfmt::{RcxBin, TargetType},
+    packet::Framing,
+};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{Cursor, Write},
+};
+
+/// Tasks and subroutines a CyberMaster program can have
+const CYBERMASTER_LIMITS: requests::ProgramLimits = requests::ProgramLimits {
+    tasks: 4,
+    subroutines: 4,
+    task_error: "Task must be 0-3",
+    subroutine_error: "Subroutine must be 0-3",
+    ..requests::RCX_LIMITS
+};
+
+/// Key the CyberMaster expects before it accepts other requests
+const UNLOCK_KEY: &[u8] = b"Do you byte, when I knock?";
+
+/// Reply to a successful unlock
+const UNLOCK_REPLY: &[u8] = b"Just a bit off the block!";
+
+/// The CyberMaster's unlock request. It shares its opcode with
+/// [`opcodes::UnlockFirmware`] but carries a longer key, so it is not
+/// part of the generated opcodes.
+#[derive(Debug)]
+struct Unlock;
+
+impl Display for Unlock {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "Unlock")
+    }
+}
+
+impl Opcode for Unlock {
+    type Response = opcodes::UnlockFirmwareResponse;
+
+    fn request_opcode(&self) -> u8 {
+        0xa5
+    }
+
+    fn response_opcode(&self) -> Option<u8> {
+        Some(0x52)
+    }
+
+    fn parse_response(reply: &[u8]) -> Result<Self::Response> {
+        opcodes::UnlockFirmwareResponse::deserialise(reply)
+    }
+
+    fn serialise(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut cursor = Cursor::new(buf);
+        cursor.write_all(UNLOCK_KEY)?;
+        Ok(cursor.position().try_into()?)
+    }
+
+    fn disasm(_bin: &[u8], _pc: &mut usize) -> Result<Self> {
+        Err(Error::Parse("The unlock request is not a bytecode"))
+    }
+}
+
+/// Index of a motor with a tachometer
+fn tacho_index(motor: MotorSelection) -> Result<u8> {
+    match motor {
+        MotorSelection::A => Ok(0),
+        MotorSelection::B => Ok(1),
+        _ => Err(Error::InvalidData("Only motors A and B have tachometers")),
+    }
+}
+
+pub struct CyberMaster {
+    rcx: Rcx,
+}
+
+impl CyberMaster {
+    /// Wake up and unlock the CyberMaster behind `tower`, which must use
+    /// [`Framing::CyberMaster`]
+    pub fn connect(tower: impl IrTower + 'static) -> Result<Self> {
+        if tower.config().framing != Framing::CyberMaster {
+            return Err(Error::InvalidData(
+                "The tower must use CyberMaster framing",
+            ));
+        }
+        let mut cybermaster = Self {
+            rcx: Rcx::new(tower),
+        };
+        cybermaster.alive()?;
+        cybermaster.unlock()?;
+        Ok(cybermaster)
+    }
+
+    pub fn alive(&mut self) -> Result<()> {
+        self.rcx.alive()
+    }
+
+    /// Download a program compiled for the CyberMaster, replacing the
+    /// current one
+    pub fn download_program(&mut self, bin: &RcxBin) -> Result<()> {
+        if bin.target_type != TargetType::CyberMaster {
+            return Err(Error::InvalidData(
+                "Program was not compiled for the CyberMaster",
+            ));
+        }
+        let sections = requests::plan_program(bin, &CYBERMASTER_LIMITS)?;
+        self.rcx.replace_program(&sections)
+    }
+
+    pub fn get_battery_power(
+        &mut self,
+    ) -> Result<opcodes::GetBatteryPowerResponse> {
+        self.rcx.get_battery_power()
+    }
+
+    pub fn get_versions(&mut self) -> Result<opcodes::GetVersionsResponse> {
+        self.rcx.get_versions()
+    }
+
+    pub fn play_sound(&mut self, sound: Sound) -> Result<()> {
+        self.rcx.play_sound(sound)
+    }
+
+    pub fn play_tone(
+        &mut self,
+        frequency_hz: i16,
+        duration_cs: i8,
+    ) -> Result<()> {
+        self.rcx.play_tone(frequency_hz, duration_cs)
+    }
+
+    pub fn power_off(&mut self) -> Result<()> {
+        self.rcx.power_off()
+    }
+
+    /// Current drawn by motor A or B
+    pub fn read_motor_current(&mut self, motor: MotorSelection) -> Result<i16> {
+        let index = tacho_index(motor)?;
+        Ok(self.rcx.get_value(SourceType::MotorCurrent, index)?.value)
+    }
+
+    /// Value of a touch sensor input (0-2)
+    pub fn read_sensor(&mut self, sensor: u8) -> Result<i16> {
+        self.rcx.read_sensor(sensor)
+    }
+
+    /// Rotation count of motor A or B since the count was last cleared
+    pub fn read_tacho_count(&mut self, motor: MotorSelection) -> Result<i16> {
+        let index = tacho_index(motor)?;
+        Ok(self.rcx.get_value(SourceType::TachoCount, index)?.value)
+    }
+
+    /// Speed of motor A or B
+    pub fn read_tacho_speed(&mut self, motor: MotorSelection) -> Result<i16> {
+        let index = tacho_index(motor)?;
+        Ok(self.rcx.get_value(SourceType::TachoSpeed, index)?.value)
+    }
+
+    pub fn read_timer(&mut self, timer: u8) -> Result<i16> {
+        self.rcx.read_timer(timer)
+    }
+
+    pub fn read_variable(&mut self, variable: u8) -> Result<i16> {
+        self.rcx.read_variable(variable)
+    }
+
+    pub fn set_motor_direction(
+        &mut self,
+        motor: MotorSelection,
+        direction: MotorDirection,
+    ) -> Result<()> {
+        self.rcx.set_motor_direction(motor, direction)
+    }
+
+    pub fn set_motor_on_off(
+        &mut self,
+        motor: MotorSelection,
+        state: MotorPowerState,
+    ) -> Result<()> {
+        self.rcx.set_motor_on_off(motor, state)
+    }
+
+    pub fn set_motor_power(
+        &mut self,
+        motor: MotorSelection,
+        power: u8,
+    ) -> Result<()> {
+        self.rcx.set_motor_power(motor, power)
+    }
+
+    pub fn set_power_down_delay(&mut self, minutes: u8) -> Result<()> {
+        self.rcx.set_power_down_delay(minutes)
+    }
+
+    pub fn set_transmitter_range(
+        &mut self,
+        range: TransmitterRange,
+    ) -> Result<()> {
+        self.rcx.set_transmitter_range(range)
+    }
+
+    pub fn start_task(&mut self, task: u8) -> Result<()> {
+        CYBERMASTER_LIMITS.check_task(task)?;
+        self.rcx.start_task(task)
+    }
+
+    pub fn stop_all_tasks(&mut self) -> Result<()> {
+        self.rcx.stop_all_tasks()
+    }
+
+    pub fn stop_task(&mut self, task: u8) -> Result<()> {
+        CYBERMASTER_LIMITS.check_task(task)?;
+        self.rcx.stop_task(task)
+    }
+
+    /// Repeat the unlock handshake, as needed after the CyberMaster has
+    /// been switched off and on again
+    pub fn unlock(&mut self) -> Result<()> {
+        let resp = self.rcx.execute(&Unlock)?;
+        if resp.data == UNLOCK_REPLY {
+            Ok(())
+        } else {
+            Err(Error::RcxError("Unexpected response from CyberMaster"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{self, bin, section, transfer};
+    use crate::tower::{
+        mock::{MockTower, Reply},
+        TowerConfig,
+    };
+    use nqc::binfmt::SectionType;
+
+    fn mock() -> MockTower {
+        fixtures::mock()
+            .with_config(TowerConfig::new().framing(Framing::CyberMaster))
+    }
+
+    #[test]
+    fn handshake() {
+        let tower = mock();
+        CyberMaster::connect(tower.clone()).unwrap();
+
+        let sent = tower.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].frame[..6], [0xfe, 0x00, 0x00, 0xff, 0x10, 0xef]);
+        assert_eq!(sent[1].opcode, 0xa5);
+        assert_eq!(sent[1].payload, UNLOCK_KEY);
+
+        // the wrong framing is refused before anything is sent
+        let tower = MockTower::new();
+        assert!(CyberMaster::connect(tower.clone()).is_err());
+        assert!(tower.sent().is_empty());
+    }
+
+    #[test]
+    fn bad_unlock_reply() {
+        let tower = mock().with_responder(|_| Reply::Payload(Vec::new()));
+        assert!(CyberMaster::connect(tower).is_err());
+    }
+
+    #[test]
+    fn motors_and_sensors() {
+        let tower = mock();
+        let mut cybermaster = CyberMaster::connect(tower.clone()).unwrap();
+        cybermaster
+            .set_motor_on_off(MotorSelection::C, MotorPowerState::On)
+            .unwrap();
+        tower.push_reply(vec![0x2a, 0x00]);
+        assert_eq!(
+            cybermaster.read_tacho_count(MotorSelection::B).unwrap(),
+            42
+        );
+        assert!(cybermaster.read_tacho_speed(MotorSelection::C).is_err());
+        assert!(cybermaster.start_task(4).is_err());
+
+        tower.assert_last_sent(&opcodes::GetValue {
+            source: SourceType::TachoCount,
+            argument: 1,
+        });
+    }
+
+    #[test]
+    fn download_program() {
+        let mut program =
+            bin(TargetType::Rcx, vec![section(SectionType::Task, 3, 4)]);
+        let tower = mock();
+        let mut cybermaster = CyberMaster::connect(tower.clone()).unwrap();
+        assert!(cybermaster.download_program(&program).is_err());
+
+        program.target_type = TargetType::CyberMaster;
+        cybermaster.download_program(&program).unwrap();
+        tower.assert_sent(&[
+            &opcodes::Alive {},
+            &Unlock,
+            &opcodes::DeleteAllTasks {},
+            &opcodes::DeleteAllSubroutines {},
+            &opcodes::StartTaskDownload {
+                reserved: 0,
+                task: 3,
+                reserved2: 0,
+                length: 4,
+            },
+            &transfer(0, &program.sections[0].data),
+        ]);
+    }
+
+    #[test]
+    fn download_is_checked_first() {
+        let tower = mock();
+        let mut cybermaster = CyberMaster::connect(tower.clone()).unwrap();
+        let handshake = tower.sent().len();
+        for (ty, number) in [
+            (SectionType::Task, CYBERMASTER_LIMITS.tasks),
+            (SectionType::Subroutine, CYBERMASTER_LIMITS.subroutines),
+        ] {
+            let program =
+                bin(TargetType::CyberMaster, vec![section(ty, number, 1)]);
+            assert!(cybermaster.download_program(&program).is_err());
+        }
+        assert_eq!(tower.sent().len(), handshake);
+    }
+}
@@ -0,0 +1,176 @@
+//! Decoding of the entries returned by `UploadDatalog`
+//!
+//! ```text
+//! * byte type - source of the entry, see below
+//! * short value
+//!
+//! Type       Description
+//! 0xff       Current datalog size
+//! 0x00-0x1f  Variable value (source 0, variables 0..31)
+//! 0x20-0x23  Timer value (source 1, timers 0..3)
+//! 0x40-0x42  Sensor reading (source 9, sensors 0..2)
+//! 0x80       Clock reading (source 14)
+//! ```
+
+use crate::{Error, Result};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::Write,
+};
+
+/// Length in bytes of a single serialised datalog entry
+pub(crate) const ENTRY_LEN: usize = 3;
+
+/// Where the value of a datalog entry came from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DatalogSource {
+    /// The number of entries in the datalog, including this one. Always
+    /// stored in entry 0.
+    Size,
+    Variable(u8),
+    Timer(u8),
+    Sensor(u8),
+    Clock,
+}
+
+impl DatalogSource {
+    fn from_type(ty: u8) -> Result<Self> {
+        Ok(match ty {
+            0xff => Self::Size,
+            0x00..=0x1f => Self::Variable(ty),
+            0x20..=0x23 => Self::Timer(ty - 0x20),
+            0x40..=0x42 => Self::Sensor(ty - 0x40),
+            0x80 => Self::Clock,
+            _ => return Err(Error::InvalidData("Unknown datalog entry type")),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Size => "size",
+            Self::Variable(_) => "variable",
+            Self::Timer(_) => "timer",
+            Self::Sensor(_) => "sensor",
+            Self::Clock => "clock",
+        }
+    }
+
+    /// Index of the variable, timer or sensor, if any
+    pub fn index(&self) -> Option<u8> {
+        match self {
+            Self::Variable(idx) | Self::Timer(idx) | Self::Sensor(idx) => {
+                Some(*idx)
+            }
+            Self::Size | Self::Clock => None,
+        }
+    }
+}
+
+impl Display for DatalogSource {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.name())?;
+        if let Some(idx) = self.index() {
+            write!(fmt, " {idx}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DatalogEntry {
+    pub source: DatalogSource,
+    pub value: i16,
+}
+
+impl DatalogEntry {
+    /// Decode a run of entries as returned by `UploadDatalog`
+    pub fn parse_all(buf: &[u8]) -> Result<Vec<Self>> {
+        if !buf.len().is_multiple_of(ENTRY_LEN) {
+            return Err(Error::InvalidData("Truncated datalog entry"));
+        }
+        buf.chunks_exact(ENTRY_LEN)
+            .map(|entry| {
+                Ok(Self {
+                    source: DatalogSource::from_type(entry[0])?,
+                    value: i16::from_le_bytes([entry[1], entry[2]]),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Write datalog entries as CSV with the columns `source,index,value`.
+/// The index is left empty for sources without one.
+pub fn write_csv(mut out: impl Write, entries: &[DatalogEntry]) -> Result<()> {
+    writeln!(out, "source,index,value")?;
+    for entry in entries {
+        let index = entry
+            .source
+            .index()
+            .map(|idx| idx.to_string())
+            .unwrap_or_default();
+        writeln!(out, "{},{index},{}", entry.source.name(), entry.value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let entries = DatalogEntry::parse_all(&[
+            0xff, 0x04, 0x00, 0x03, 0xfe, 0xff, 0x41, 0x20, 0x03, 0x80, 0x05,
+            0x00,
+        ])
+        .unwrap();
+        assert_eq!(
+            entries,
+            [
+                DatalogEntry {
+                    source: DatalogSource::Size,
+                    value: 4
+                },
+                DatalogEntry {
+                    source: DatalogSource::Variable(3),
+                    value: -2
+                },
+                DatalogEntry {
+                    source: DatalogSource::Sensor(1),
+                    value: 800
+                },
+                DatalogEntry {
+                    source: DatalogSource::Clock,
+                    value: 5
+                },
+            ]
+        );
+
+        assert!(DatalogEntry::parse_all(&[0x60, 0, 0]).is_err());
+        assert!(DatalogEntry::parse_all(&[0x80, 0]).is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let mut out = Vec::new();
+        write_csv(
+            &mut out,
+            &[
+                DatalogEntry {
+                    source: DatalogSource::Timer(2),
+                    value: 150,
+                },
+                DatalogEntry {
+                    source: DatalogSource::Clock,
+                    value: 12,
+                },
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "source,index,value\ntimer,2,150\nclock,,12\n"
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Towers and programs shared by the client tests
+
+use crate::{
+    opcodes,
+    tower::mock::{MockTower, Reply},
+};
+use nqc::binfmt::{RcxBin, Section, SectionType, TargetType};
+
+/// Acknowledge every request, replying with a zero error code to the
+/// download requests and with the brick's answer to `UnlockFirmware`
+pub(crate) fn mock() -> MockTower {
+    MockTower::new().with_responder(|sent| match sent.opcode {
+        0x25 | 0x35 | 0x45 | 0x75 => Reply::Payload(vec![0]),
+        0xa5 => Reply::Payload(b"Just a bit off the block!".to_vec()),
+        _ => Reply::Payload(Vec::new()),
+    })
+}
+
+/// A program compiled for `target`
+pub(crate) fn bin(target_type: TargetType, sections: Vec<Section>) -> RcxBin {
+    RcxBin {
+        signature: *b"RCXI",
+        version: 0x0102,
+        section_count: sections.len() as u16,
+        symbol_count: 0,
+        target_type,
+        reserved: 0,
+        sections,
+        symbols: Vec::new(),
+    }
+}
+
+/// A section of `len` bytes counting up from 0
+pub(crate) fn section(ty: SectionType, number: u8, len: usize) -> Section {
+    Section {
+        ty,
+        number,
+        length: len as u16,
+        data: (0..len).map(|byte| byte as u8).collect(),
+    }
+}
+
+/// The block carrying `data` in a program download
+pub(crate) fn transfer(index: i16, data: &[u8]) -> opcodes::TransferData {
+    opcodes::TransferData {
+        index,
+        length: data.len() as i16,
+        data: data.to_vec(),
+        checksum: data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)),
+    }
+}
@@ -1,74 +1,210 @@
-#![doc = include_str!("../README.md")]
-
+// the README example opens a USB tower
+#![cfg_attr(feature = "usbtower", doc = include_str!("../README.md"))]
+#![cfg_attr(
+    not(feature = "usbtower"),
+    doc = "Interface library for communicating with the LEGO Mindstorms RCX brick"
+)]
+
+#[cfg(feature = "tokio")]
+mod async_rcx;
+pub mod cybermaster;
+pub mod datalog;
+#[cfg(test)]
+mod fixtures;
+pub mod lnp;
+pub mod memory;
+pub mod message;
+mod requests;
+pub mod scout;
+pub mod spybot;
 pub mod tower;
 
+#[cfg(feature = "tokio")]
+pub use async_rcx::AsyncRcx;
+use datalog::DatalogEntry;
 pub use errors::{Error, Result};
 pub use nqc::enums::*;
 pub use nqc::errors;
-use nqc::opcodes;
+pub use nqc::opcodes;
+use nqc::{binfmt::RcxBin, srec};
 
+use requests::check_download;
 use tower::IrTower;
 
 pub struct Rcx {
     tower: Box<dyn IrTower>,
+    /// Transmitter range from the tower config which has not been sent
+    /// to the brick yet
+    pending_range: Option<TransmitterRange>,
 }
 
 impl Rcx {
     pub fn new(tower: impl IrTower + 'static) -> Self {
         Self {
+            pending_range: tower.config().range,
             tower: Box::new(tower),
         }
     }
 
+    /// Send a request through the tower, first selecting the configured
+    /// transmitter range if that has not been done yet
+    fn send_recv(&mut self, msg: &dyn opcodes::Opcode) -> Result<Vec<u8>> {
+        if let Some(range) = self.pending_range {
+            self.tower.send_recv(&opcodes::SetTransmitterRange {
+                range: range as u8,
+            })?;
+            self.pending_range = None;
+        }
+        self.tower.send_recv(msg)
+    }
+
     pub fn alive(&mut self) -> Result<()> {
-        self.tower.send_recv(&opcodes::Alive {})?;
-        Ok(())
+        self.execute(&opcodes::Alive {})
+    }
+
+    /// Add an entry to the datalog with the value of a variable, timer,
+    /// sensor or the clock
+    pub fn datalog_next(
+        &mut self,
+        source: SourceType,
+        argument: u8,
+    ) -> Result<()> {
+        let resp = self.execute(&requests::datalog_next(source, argument)?)?;
+        requests::check_datalog_next(resp.errorcode)
     }
 
     pub fn delete_all_subroutines(&mut self) -> Result<()> {
-        self.tower.send_recv(&opcodes::DeleteAllSubroutines {})?;
-        Ok(())
+        self.execute(&opcodes::DeleteAllSubroutines {})
     }
 
     pub fn delete_all_tasks(&mut self) -> Result<()> {
-        self.tower.send_recv(&opcodes::DeleteAllTasks {})?;
-        Ok(())
+        self.execute(&opcodes::DeleteAllTasks {})
     }
 
     pub fn delete_firmware(&mut self) -> Result<()> {
-        self.tower.send_recv(&opcodes::DeleteFirmware {
-            key: [1, 3, 5, 7, 11],
-        })?;
-        Ok(())
+        self.execute(&requests::delete_firmware())
     }
 
     pub fn delete_subroutine(&mut self, subroutine: u8) -> Result<()> {
-        if subroutine > 7 {
-            return Err(Error::InvalidData("Subroutine must be 0-7"));
+        self.execute(&requests::delete_subroutine(subroutine)?)
+    }
+
+    pub fn delete_task(&mut self, task: u8) -> Result<()> {
+        self.execute(&requests::delete_task(task)?)
+    }
+
+    /// Download a parsed `.rcx` image into the given program slot (0-4).
+    ///
+    /// Every section is checked before anything is sent, then all
+    /// existing tasks and subroutines in the slot are deleted. If a
+    /// section is rejected or fails to download, the returned
+    /// [`Error::SectionDownload`] identifies which one.
+    pub fn download_program(&mut self, slot: u8, bin: &RcxBin) -> Result<()> {
+        let program = requests::set_program_number(slot)?;
+        let sections = requests::plan_program(bin, &requests::RCX_LIMITS)?;
+        self.execute(&program)?;
+        self.replace_program(&sections)
+    }
+
+    /// Delete every task and subroutine on the brick, then download the
+    /// planned sections in their place
+    pub(crate) fn replace_program(
+        &mut self,
+        sections: &[requests::SectionPlan<'_>],
+    ) -> Result<()> {
+        self.delete_all_tasks()?;
+        self.delete_all_subroutines()?;
+        for plan in sections {
+            self.download_section(plan).map_err(|source| {
+                requests::section_error(plan.section, source)
+            })?;
         }
-        self.tower
-            .send_recv(&opcodes::DeleteSubroutine { subroutine })?;
         Ok(())
     }
 
-    pub fn delete_task(&mut self, task: u8) -> Result<()> {
-        if task > 9 {
-            return Err(Error::InvalidData("Task must be 0-9"));
+    fn download_section(
+        &mut self,
+        plan: &requests::SectionPlan<'_>,
+    ) -> Result<()> {
+        let errorcode = match &plan.start {
+            requests::SectionDownload::Task(msg) => {
+                self.execute(msg)?.errorcode
+            }
+            requests::SectionDownload::Subroutine(msg) => {
+                self.execute(msg)?.errorcode
+            }
+        };
+        check_download(errorcode)?;
+        for block in &plan.blocks {
+            check_download(self.execute(block)?.errorcode)?;
         }
-        self.tower.send_recv(&opcodes::DeleteTask { task })?;
         Ok(())
     }
 
+    /// Replace the firmware on the brick with the given S-record image,
+    /// e.g. one parsed from `firm0332.lgo`.
+    ///
+    /// `progress` is called after every acknowledged block with the
+    /// number of bytes sent so far and the total image size. A full
+    /// download takes several minutes.
+    pub fn download_firmware(
+        &mut self,
+        image: &srec::Image,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        let (start, mut download) = requests::FirmwareDownload::new(image)?;
+
+        self.delete_firmware()?;
+        check_download(self.execute(&start)?.errorcode)?;
+
+        while let Some(block) = download.next_block() {
+            let result = self
+                .execute(&block?)
+                .and_then(|resp| check_download(resp.errorcode));
+            if let Some((sent, total)) = download.complete(result)? {
+                progress(sent, total);
+            }
+        }
+
+        self.unlock_firmware()
+    }
+
+    /// Send any request and parse its reply, including requests which
+    /// have no method of their own.
+    ///
+    /// ```
+    /// # fn main() -> rcx::Result<()> {
+    /// use rcx::{opcodes, tower::mock::MockTower, Rcx};
+    ///
+    /// let tower = MockTower::new();
+    /// tower.push_reply(Vec::new());
+    /// tower.push_reply(vec![0x43, 0x1e]);
+    ///
+    /// let mut rcx = Rcx::new(tower);
+    /// rcx.execute(&opcodes::ClearTimer { timer: 0 })?;
+    /// let battery = rcx.execute(&opcodes::GetBatteryPower {})?;
+    /// assert_eq!(battery.millivolts, 7747);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn execute<O: opcodes::Opcode>(
+        &mut self,
+        msg: &O,
+    ) -> Result<O::Response> {
+        let reply = self.send_recv(msg)?;
+        O::parse_response(&reply)
+    }
+
     pub fn get_battery_power(
         &mut self,
     ) -> Result<opcodes::GetBatteryPowerResponse> {
-        let resp = self.tower.send_recv(&opcodes::GetBatteryPower {})?;
-        opcodes::GetBatteryPowerResponse::deserialise(&resp)
+        self.execute(&opcodes::GetBatteryPower {})
     }
 
-    pub fn get_memory_map(&mut self) -> Result<opcodes::GetMemoryMapResponse> {
-        let resp = self.tower.send_recv(&opcodes::GetMemoryMap {})?;
-        opcodes::GetMemoryMapResponse::deserialise(&resp)
+    /// Fetch the memory map, giving where each task and subroutine is
+    /// stored and how much memory remains
+    pub fn get_memory_map(&mut self) -> Result<memory::MemoryMap> {
+        Ok(self.execute(&opcodes::GetMemoryMap {})?.into())
     }
 
     pub fn get_value(
@@ -76,23 +212,21 @@ impl Rcx {
         source: SourceType,
         argument: u8,
     ) -> Result<opcodes::GetValueResponse> {
-        let resp = self.tower.send_recv(&opcodes::GetValue {
-            source: source as u8,
-            argument,
-        })?;
-        opcodes::GetValueResponse::deserialise(&resp)
+        self.execute(&requests::get_value(source, argument)?)
     }
 
     pub fn get_versions(&mut self) -> Result<opcodes::GetVersionsResponse> {
-        const KEY: [u8; 5] = [1, 3, 5, 7, 11];
-        let resp = self.tower.send_recv(&opcodes::GetVersions { key: KEY })?;
-        opcodes::GetVersionsResponse::deserialise(&resp)
+        self.execute(&requests::get_versions())
+    }
+
+    /// Listen for messages broadcast by other bricks. Send messages to
+    /// them with [`Self::set_message`].
+    pub fn messages(&mut self) -> message::Messages<'_> {
+        message::Messages::new(&mut *self.tower)
     }
 
     pub fn play_sound(&mut self, sound: Sound) -> Result<()> {
-        self.tower
-            .send_recv(&opcodes::PlaySound { sound: sound as u8 })?;
-        Ok(())
+        self.execute(&opcodes::PlaySound { sound })
     }
 
     pub fn play_tone(
@@ -100,16 +234,70 @@ impl Rcx {
         frequency_hz: i16,
         duration_cs: i8,
     ) -> Result<()> {
-        self.tower.send_recv(&opcodes::PlayTone {
+        self.execute(&opcodes::PlayTone {
             frequency: frequency_hz,
             duration: duration_cs,
-        })?;
-        Ok(())
+        })
     }
 
     pub fn power_off(&mut self) -> Result<()> {
-        self.tower.send_recv(&opcodes::PowerOff {})?;
-        Ok(())
+        self.execute(&opcodes::PowerOff {})
+    }
+
+    /// Minutes since the brick was powered on
+    pub fn read_clock(&mut self) -> Result<i16> {
+        Ok(self.get_value(SourceType::Clock, 0)?.value)
+    }
+
+    /// Contents of the message buffer
+    pub fn read_message(&mut self) -> Result<u8> {
+        Ok(self.get_value(SourceType::Message, 0)?.value as u8)
+    }
+
+    pub fn read_motor_state(
+        &mut self,
+        motor: MotorSelection,
+    ) -> Result<MotorState> {
+        let index = requests::motor_index(motor)?;
+        let value = self.get_value(SourceType::MotorState, index)?.value;
+        MotorState::try_from(value as u8)
+    }
+
+    /// Raw value of a sensor (0-2), 0..1023
+    pub fn read_raw_sensor(&mut self, sensor: u8) -> Result<i16> {
+        let msg = requests::read_sensor(SourceType::RawSensorValue, sensor)?;
+        Ok(self.execute(&msg)?.value)
+    }
+
+    /// Value of a sensor (0-2), scaled according to its mode
+    pub fn read_sensor(&mut self, sensor: u8) -> Result<i16> {
+        let msg = requests::read_sensor(SourceType::SensorValue, sensor)?;
+        Ok(self.execute(&msg)?.value)
+    }
+
+    /// Value of a timer (0-3), in 1/100ths of a second
+    pub fn read_timer(&mut self, timer: u8) -> Result<i16> {
+        Ok(self.execute(&requests::read_timer(timer)?)?.value)
+    }
+
+    pub fn read_variable(&mut self, variable: u8) -> Result<i16> {
+        Ok(self.execute(&requests::read_variable(variable)?)?.value)
+    }
+
+    /// Act as the LEGO remote control with `buttons` held down. Motors
+    /// keep running until the buttons are released by sending
+    /// [`RemoteButtons::NONE`].
+    pub fn remote(&mut self, buttons: RemoteButtons) -> Result<()> {
+        self.execute(&opcodes::Remote {
+            buttons: buttons.bitfield.to_be_bytes(),
+        })
+    }
+
+    /// Allocate a new, empty datalog with space for `size` entries,
+    /// discarding the current one. A size of 0 frees the datalog.
+    pub fn set_datalog_size(&mut self, size: i16) -> Result<()> {
+        let resp = self.execute(&requests::set_datalog_size(size)?)?;
+        requests::check_set_datalog_size(resp.errorcode)
     }
 
     pub fn set_display(
@@ -117,15 +305,11 @@ impl Rcx {
         source: SourceType,
         argument: u8,
     ) -> Result<()> {
-        self.tower.send_recv(&opcodes::SetDisplay {
-            source: source as u8,
-            argument,
-        })?;
-        Ok(())
+        self.execute(&opcodes::SetDisplay { source, argument })
     }
 
     pub fn set_message(&mut self, message: u8) -> Result<()> {
-        self.tower.send(&opcodes::SetMessage { message })
+        self.execute(&opcodes::SetMessage { message })
     }
 
     pub fn set_motor_direction(
@@ -133,13 +317,7 @@ impl Rcx {
         motor: MotorSelection,
         direction: MotorDirection,
     ) -> Result<()> {
-        let mut bitfield = motor.bitfield;
-        if direction == MotorDirection::Forward {
-            bitfield |= 0x80;
-        }
-        self.tower
-            .send_recv(&opcodes::SetMotorDirection { code: bitfield })?;
-        Ok(())
+        self.execute(&requests::set_motor_direction(motor, direction))
     }
 
     pub fn set_motor_on_off(
@@ -147,14 +325,7 @@ impl Rcx {
         motor: MotorSelection,
         state: MotorPowerState,
     ) -> Result<()> {
-        let mut code = motor.bitfield;
-        match state {
-            MotorPowerState::On => code |= 0x80,
-            MotorPowerState::Off => code |= 0x40,
-            MotorPowerState::Float => {}
-        }
-        self.tower.send_recv(&opcodes::SetMotorOnOff { code })?;
-        Ok(())
+        self.execute(&requests::set_motor_on_off(motor, state))
     }
 
     pub fn set_motor_power(
@@ -162,30 +333,15 @@ impl Rcx {
         motor: MotorSelection,
         power: u8,
     ) -> Result<()> {
-        if power > 7 {
-            return Err(Error::InvalidData("Motor power must be 0-7"));
-        }
-        self.tower.send_recv(&opcodes::SetMotorPower {
-            motors: motor.bitfield,
-            source: SourceType::Immediate as u8,
-            argument: power,
-        })?;
-        Ok(())
+        self.execute(&requests::set_motor_power(motor, power)?)
     }
 
     pub fn set_power_down_delay(&mut self, minutes: u8) -> Result<()> {
-        self.tower
-            .send_recv(&opcodes::SetPowerDownDelay { minutes })?;
-        Ok(())
+        self.execute(&opcodes::SetPowerDownDelay { minutes })
     }
 
     pub fn set_program_number(&mut self, program: u8) -> Result<()> {
-        if program > 4 {
-            return Err(Error::InvalidData("Program number must be 0-4"));
-        }
-        self.tower
-            .send_recv(&opcodes::SetProgramNumber { program })?;
-        Ok(())
+        self.execute(&requests::set_program_number(program)?)
     }
 
     pub fn set_sensor_mode(
@@ -193,14 +349,7 @@ impl Rcx {
         sensor: u8,
         mode: SensorMode,
     ) -> Result<()> {
-        if sensor > 2 {
-            return Err(Error::InvalidData("Sensor index must be 0-2"));
-        }
-        self.tower.send_recv(&opcodes::SetSensorMode {
-            sensor,
-            code: mode as u8,
-        })?;
-        Ok(())
+        self.execute(&requests::set_sensor_mode(sensor, mode)?)
     }
 
     pub fn set_sensor_type(
@@ -208,124 +357,495 @@ impl Rcx {
         sensor: u8,
         ty: SensorType,
     ) -> Result<()> {
-        if sensor > 2 {
-            return Err(Error::InvalidData("Sensor index must be 0-2"));
-        }
-        self.tower.send_recv(&opcodes::SetSensorType {
-            sensor,
-            type_: ty as u8,
-        })?;
-        Ok(())
+        self.execute(&requests::set_sensor_type(sensor, ty)?)
     }
 
     pub fn set_time(&mut self, hours: u8, minutes: u8) -> Result<()> {
-        if hours > 23 || minutes > 59 {
-            return Err(Error::InvalidData(
-                "Hours must be 0-23 and minutes must be 0-59",
-            ));
-        }
-        self.tower.send_recv(&opcodes::SetTime { hours, minutes })?;
-        Ok(())
+        self.execute(&requests::set_time(hours, minutes)?)
     }
 
     pub fn set_transmitter_range(
         &mut self,
         range: TransmitterRange,
     ) -> Result<()> {
-        self.tower
-            .send_recv(&opcodes::SetTransmitterRange { range: range as u8 })?;
-        Ok(())
+        self.pending_range = None;
+        self.execute(&opcodes::SetTransmitterRange { range: range as u8 })
     }
 
     pub fn start_firmware_download(
         &mut self,
         address: i16,
         checksum: i16,
-    ) -> Result<opcodes::StartFirmwareDownloadResponse> {
-        let resp = self.tower.send_recv(&opcodes::StartFirmwareDownload {
+    ) -> Result<()> {
+        let resp = self.execute(&opcodes::StartFirmwareDownload {
             address,
             checksum,
             unknown: 0,
         })?;
-        opcodes::StartFirmwareDownloadResponse::deserialise(&resp)
+        check_download(resp.errorcode)
     }
 
+    /// Allocate space for a subroutine of the current program, to be
+    /// sent with [`Self::transfer_data`]. Fails with
+    /// [`Error::OutOfMemory`] if there is not enough space.
     pub fn start_subroutine_download(
         &mut self,
         subroutine: u8,
         length: i16,
-    ) -> Result<opcodes::StartSubroutineDownloadResponse> {
-        if subroutine > 7 {
-            return Err(Error::InvalidData("Subroutine must be 0-7"));
-        }
-        let resp = self.tower.send_recv(&opcodes::StartSubroutineDownload {
-            reserved: 0,
-            subroutine,
-            reserved2: 0,
-            length,
-        })?;
-        opcodes::StartSubroutineDownloadResponse::deserialise(&resp)
+    ) -> Result<()> {
+        let msg = requests::start_subroutine_download(subroutine, length)?;
+        check_download(self.execute(&msg)?.errorcode)
     }
 
     pub fn start_task(&mut self, task: u8) -> Result<()> {
-        if task > 9 {
-            return Err(Error::InvalidData("Task must be 0-9"));
-        }
-        self.tower.send_recv(&opcodes::StartTask { task })?;
-        Ok(())
+        self.execute(&requests::start_task(task)?)
     }
 
+    /// Allocate space for a task of the current program, to be sent
+    /// with [`Self::transfer_data`]. Fails with [`Error::OutOfMemory`]
+    /// if there is not enough space.
     pub fn start_task_download(&mut self, task: u8, length: i16) -> Result<()> {
-        if task > 9 {
-            return Err(Error::InvalidData("Task must be 0-9"));
-        }
-        self.tower.send_recv(&opcodes::StartTaskDownload {
-            reserved: 0,
-            task,
-            reserved2: 0,
-            length,
-        })?;
-        Ok(())
+        let msg = requests::start_task_download(task, length)?;
+        check_download(self.execute(&msg)?.errorcode)
     }
 
     pub fn stop_all_tasks(&mut self) -> Result<()> {
-        self.tower.send_recv(&opcodes::StopAllTasks {})?;
-        Ok(())
+        self.execute(&opcodes::StopAllTasks {})
     }
 
     pub fn stop_task(&mut self, task: u8) -> Result<()> {
-        if task > 9 {
-            return Err(Error::InvalidData("Task must be 0-9"));
-        }
-        self.tower.send_recv(&opcodes::StopTask { task })?;
-        Ok(())
+        self.execute(&requests::stop_task(task)?)
     }
 
+    /// Send a block of the download in progress. Fails with
+    /// [`Error::BlockChecksum`] if the block was corrupted on the way, in
+    /// which case it may be sent again.
     pub fn transfer_data(
         &mut self,
         index: i16,
         length: i16,
         data: Vec<u8>,
         checksum: u8,
-    ) -> Result<opcodes::TransferDataResponse> {
-        let resp = self.tower.send_recv(&opcodes::TransferData {
+    ) -> Result<()> {
+        let resp = self.execute(&opcodes::TransferData {
             index,
             length,
             data,
             checksum,
         })?;
-        opcodes::TransferDataResponse::deserialise(&resp)
+        check_download(resp.errorcode)
     }
 
     pub fn unlock_firmware(&mut self) -> Result<()> {
-        let resp = self
-            .tower
-            .send_recv(&opcodes::UnlockFirmware { key: *b"LEGO\xae" })?;
-        let resp = opcodes::UnlockFirmwareResponse::deserialise(&resp)?;
-        if &resp.data == b"Just a bit off the block!" {
-            Ok(())
-        } else {
-            Err(Error::RcxError("Unexpected response from brick"))
+        let resp = self.execute(&requests::unlock_firmware())?;
+        requests::check_unlock_firmware(&resp)
+    }
+
+    /// Fetch the entire datalog, in as few requests as the brick allows.
+    /// The size entry is not included in the result.
+    pub fn upload_datalog(&mut self) -> Result<Vec<DatalogEntry>> {
+        let size = requests::datalog_size(&self.upload_datalog_entries(0, 1)?)?;
+        let mut entries = Vec::new();
+        for (first, count) in requests::datalog_chunks(size) {
+            entries.extend(self.upload_datalog_entries(first, count)?);
         }
+        Ok(entries)
+    }
+
+    fn upload_datalog_entries(
+        &mut self,
+        first: i16,
+        count: i16,
+    ) -> Result<Vec<DatalogEntry>> {
+        let resp = self.execute(&opcodes::UploadDatalog { first, count })?;
+        requests::parse_datalog(&resp, count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures::{bin, mock, section, transfer};
+    use nqc::binfmt::{SectionType, TargetType};
+    use tower::mock::{MockTower, Reply};
+
+    #[test]
+    fn alive() {
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        rcx.alive().unwrap();
+        tower.assert_sent(&[&opcodes::Alive {}]);
+    }
+
+    #[test]
+    fn configured_range() {
+        let tower = mock().with_config(
+            tower::TowerConfig::new().range(TransmitterRange::Long),
+        );
+        let mut rcx = Rcx::new(tower.clone());
+        rcx.alive().unwrap();
+        rcx.alive().unwrap();
+        tower.assert_sent(&[
+            &opcodes::SetTransmitterRange { range: 1 },
+            &opcodes::Alive {},
+            &opcodes::Alive {},
+        ]);
+    }
+
+    #[test]
+    fn upload_datalog() {
+        // a datalog holding 120 entries of variable 7, valued by index
+        let tower = MockTower::new().with_responder(|sent| {
+            let first = i16::from_le_bytes([sent.payload[0], sent.payload[1]]);
+            let count = i16::from_le_bytes([sent.payload[2], sent.payload[3]]);
+            let mut data = Vec::new();
+            for idx in first..first + count {
+                let ty = if idx == 0 { 0xff } else { 7 };
+                let value = if idx == 0 { 121 } else { idx };
+                data.push(ty);
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            Reply::Payload(data)
+        });
+        let mut rcx = Rcx::new(tower.clone());
+
+        let entries = rcx.upload_datalog().unwrap();
+        assert_eq!(entries.len(), 120);
+        assert!(entries.iter().enumerate().all(|(idx, entry)| {
+            entry.source == datalog::DatalogSource::Variable(7)
+                && entry.value == idx as i16 + 1
+        }));
+        tower.assert_sent(&[
+            &opcodes::UploadDatalog { first: 0, count: 1 },
+            &opcodes::UploadDatalog {
+                first: 1,
+                count: 50,
+            },
+            &opcodes::UploadDatalog {
+                first: 51,
+                count: 50,
+            },
+            &opcodes::UploadDatalog {
+                first: 101,
+                count: 20,
+            },
+        ]);
+    }
+
+    #[test]
+    fn read_values() {
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+
+        tower.push_reply(vec![0xd2, 0x04]);
+        assert_eq!(rcx.read_variable(31).unwrap(), 1234);
+        tower.push_reply(vec![0x8d, 0x00]);
+        assert_eq!(
+            rcx.read_motor_state(MotorSelection::C).unwrap(),
+            MotorState {
+                power: 5,
+                direction: MotorDirection::Reverse,
+                state: MotorPowerState::On,
+            }
+        );
+        tower.push_reply(vec![0xc0, 0x00]);
+        assert!(rcx.read_motor_state(MotorSelection::A).is_err());
+        tower.assert_sent(&[
+            &opcodes::GetValue {
+                source: SourceType::Variable,
+                argument: 31,
+            },
+            &opcodes::GetValue {
+                source: SourceType::MotorState,
+                argument: 2,
+            },
+            &opcodes::GetValue {
+                source: SourceType::MotorState,
+                argument: 0,
+            },
+        ]);
+
+        tower.clear();
+        assert!(rcx.read_variable(32).is_err());
+        assert!(rcx.read_timer(4).is_err());
+        assert!(rcx.read_sensor(3).is_err());
+        assert!(rcx
+            .read_motor_state(MotorSelection::A | MotorSelection::B)
+            .is_err());
+        assert!(rcx.get_value(SourceType::Random, 10).is_err());
+        tower.assert_sent(&[]);
+    }
+
+    #[test]
+    fn get_battery_power() {
+        let tower = mock();
+        tower.push_reply(vec![0x43, 0x1e]);
+        let mut rcx = Rcx::new(tower.clone());
+        assert_eq!(rcx.get_battery_power().unwrap().millivolts, 7747);
+        tower.assert_sent(&[&opcodes::GetBatteryPower {}]);
+    }
+
+    #[test]
+    fn execute() {
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        // not wrapped by a method of its own
+        rcx.execute(&opcodes::ClearTimer { timer: 2 }).unwrap();
+        tower.push_reply(vec![0x43, 0x1e]);
+        let battery = rcx.execute(&opcodes::GetBatteryPower {}).unwrap();
+        assert_eq!(battery.millivolts, 7747);
+
+        // the reply must match the request
+        tower.push_frame(nqc::packet::Packet::new(0xcf, vec![0x43]).encode());
+        assert!(rcx.execute(&opcodes::GetBatteryPower {}).is_err());
+
+        // requests only known at runtime return the reply packet
+        let request =
+            opcodes::Opcodes::SetMessage(opcodes::SetMessage { message: 3 });
+        assert_eq!(rcx.execute(&request).unwrap(), None);
+        let request =
+            opcodes::Opcodes::ClearTimer(opcodes::ClearTimer { timer: 0 });
+        let reply = rcx.execute(&request).unwrap().unwrap();
+        assert_eq!(reply.opcode | 0x08, 0x5e);
+    }
+
+    #[test]
+    fn motors() {
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        rcx.set_motor_direction(
+            MotorSelection::A | MotorSelection::C,
+            MotorDirection::Forward,
+        )
+        .unwrap();
+        rcx.set_motor_power(MotorSelection::B, 7).unwrap();
+        rcx.set_motor_on_off(MotorSelection::A, MotorPowerState::Off)
+            .unwrap();
+        tower.assert_sent(&[
+            &opcodes::SetMotorDirection {
+                code: MotorDirectionCode {
+                    motors: MotorSelection::A | MotorSelection::C,
+                    direction: MotorDirectionChange::Forward,
+                },
+            },
+            &opcodes::SetMotorPower {
+                motors: MotorSelection::B,
+                source: SourceType::Immediate,
+                argument: 7,
+            },
+            &opcodes::SetMotorOnOff {
+                code: MotorOnOffCode {
+                    motors: MotorSelection::A,
+                    state: MotorPowerState::Off,
+                },
+            },
+        ]);
+    }
+
+    #[test]
+    fn argument_validation() {
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        assert!(rcx.set_motor_power(MotorSelection::A, 8).is_err());
+        assert!(rcx.delete_task(10).is_err());
+        assert!(rcx.delete_subroutine(8).is_err());
+        assert!(rcx.set_program_number(5).is_err());
+        assert!(rcx.set_sensor_type(3, SensorType::Light).is_err());
+        assert!(rcx.set_time(24, 0).is_err());
+        assert!(tower.sent().is_empty());
+    }
+
+    #[test]
+    fn set_message_has_no_reply() {
+        let tower = mock().with_responder(|_| Reply::Timeout);
+        let mut rcx = Rcx::new(tower.clone());
+        rcx.set_message(42).unwrap();
+        tower.assert_sent(&[&opcodes::SetMessage { message: 42 }]);
+    }
+
+    #[test]
+    fn remote() {
+        let tower = mock().with_responder(|_| Reply::Timeout);
+        let mut rcx = Rcx::new(tower.clone());
+        rcx.remote(
+            RemoteButtons::MOTOR_A_FORWARD | RemoteButtons::MOTOR_C_REVERSE,
+        )
+        .unwrap();
+        rcx.remote(RemoteButtons::NONE).unwrap();
+        tower.assert_sent(&[
+            &opcodes::Remote {
+                buttons: [0x08, 0x01],
+            },
+            &opcodes::Remote {
+                buttons: [0x00, 0x00],
+            },
+        ]);
+        assert_eq!(
+            tower.sent()[0].frame,
+            [
+                0x55, 0xff, 0x00, 0xd2, 0x2d, 0x08, 0xf7, 0x01, 0xfe, 0xdb,
+                0x24
+            ]
+        );
+    }
+
+    #[test]
+    fn unlock_firmware_bad_reply() {
+        let tower = mock();
+        tower.push_reply(b"Just a bit off the block?".to_vec());
+        let mut rcx = Rcx::new(tower);
+        assert!(matches!(rcx.unlock_firmware(), Err(Error::RcxError(_))));
+    }
+
+    #[test]
+    fn download_program() {
+        let bin = bin(
+            TargetType::Rcx,
+            vec![
+                section(SectionType::Subroutine, 2, 4),
+                section(SectionType::Task, 0, 45),
+            ],
+        );
+        let sub = &bin.sections[0].data;
+        let task = &bin.sections[1].data;
+
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        rcx.download_program(3, &bin).unwrap();
+
+        tower.assert_sent(&[
+            &opcodes::SetProgramNumber { program: 3 },
+            &opcodes::DeleteAllTasks {},
+            &opcodes::DeleteAllSubroutines {},
+            &opcodes::StartSubroutineDownload {
+                reserved: 0,
+                subroutine: 2,
+                reserved2: 0,
+                length: 4,
+            },
+            &transfer(0, sub),
+            &opcodes::StartTaskDownload {
+                reserved: 0,
+                task: 0,
+                reserved2: 0,
+                length: 45,
+            },
+            &transfer(1, &task[..20]),
+            &transfer(2, &task[20..40]),
+            &transfer(0, &task[40..]),
+        ]);
+    }
+
+    #[test]
+    fn download_program_reports_section() {
+        let bin = bin(TargetType::Rcx, vec![section(SectionType::Sound, 1, 1)]);
+
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        let err = rcx.download_program(0, &bin).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::SectionDownload {
+                ty: SectionType::Sound,
+                number: 1,
+                ..
+            }
+        ));
+        assert!(tower.sent().is_empty());
+    }
+
+    #[test]
+    fn download_firmware() {
+        let image = srec::Image {
+            start: 0x8000,
+            entry: 0x8000,
+            data: (0..=255).cycle().take(450).collect(),
+        };
+        let mut first_block = true;
+        let tower =
+            MockTower::new().with_responder(move |sent| match sent.opcode {
+                // fail the first block once, which forces a retransmit with
+                // an incremented sequence number
+                0x45 if first_block => {
+                    first_block = false;
+                    Reply::Payload(vec![3])
+                }
+                0x45 | 0x75 => Reply::Payload(vec![0]),
+                0xa5 => Reply::Payload(b"Just a bit off the block!".to_vec()),
+                _ => Reply::Payload(Vec::new()),
+            });
+        let mut rcx = Rcx::new(tower.clone());
+
+        let mut progress = Vec::new();
+        rcx.download_firmware(&image, |sent, total| {
+            progress.push((sent, total))
+        })
+        .unwrap();
+
+        assert_eq!(progress, vec![(200, 450), (400, 450), (450, 450)]);
+
+        let sum = image.checksum() as i16;
+        tower.assert_sent(&[
+            &opcodes::DeleteFirmware {
+                key: [1, 3, 5, 7, 11],
+            },
+            &opcodes::StartFirmwareDownload {
+                address: 0x8000u16 as i16,
+                checksum: sum,
+                unknown: 0,
+            },
+            &transfer(1, &image.data[..200]),
+            &transfer(2, &image.data[..200]),
+            &transfer(3, &image.data[200..400]),
+            &transfer(0, &image.data[400..]),
+            &opcodes::UnlockFirmware { key: *b"LEGO\xae" },
+        ]);
+    }
+
+    #[test]
+    fn download_firmware_last_block_not_retried() {
+        let image = srec::Image {
+            start: 0x8000,
+            entry: 0x8000,
+            data: vec![1; 10],
+        };
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        tower.push_reply(Vec::new());
+        tower.push_reply(vec![0]);
+        tower.push_reply(vec![3]);
+
+        assert!(matches!(
+            rcx.download_firmware(&image, |_, _| {}),
+            Err(Error::BlockChecksum)
+        ));
+        assert_eq!(tower.sent().len(), 3);
+    }
+
+    #[test]
+    fn download_errors() {
+        let tower = mock();
+        let mut rcx = Rcx::new(tower.clone());
+        for (code, expected) in [
+            (1, Error::OutOfMemory),
+            (2, Error::IllegalIndex),
+            (6, Error::NoDownloadInProgress),
+            (9, Error::UnknownDownloadError(9)),
+        ] {
+            tower.push_reply(vec![code]);
+            let err = rcx.start_task_download(0, 10).unwrap_err();
+            assert_eq!(err.to_string(), expected.to_string());
+        }
+
+        tower.push_reply(vec![1]);
+        assert!(matches!(
+            rcx.start_subroutine_download(0, 10),
+            Err(Error::OutOfMemory)
+        ));
+        tower.push_reply(vec![4]);
+        assert!(matches!(
+            rcx.transfer_data(0, 1, vec![1], 1),
+            Err(Error::FirmwareChecksum)
+        ));
     }
 }
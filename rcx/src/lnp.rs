@@ -0,0 +1,376 @@
+//! The LegOS Network Protocol (LNP), spoken by bricks running brickOS
+//!
+//! ```text
+//! * integrity packet - 0xf0, length, data, checksum
+//! * addressed packet - 0xf1, length, destination, source, data,
+//!   checksum
+//! ```
+//!
+//! The length counts the bytes between it and the checksum, and the
+//! checksum is 0xff plus the sum of every byte before it. An address
+//! holds a host in its high nibble and a port in its low nibble.
+//!
+//! LNP packets are sent without the RCX header and complement bytes, so
+//! [`LnpSocket`] needs a tower using [`Framing::Raw`].
+//!
+//! ```no_run
+//! # #[cfg(not(feature = "usbtower"))]
+//! # fn main() {}
+//! # #[cfg(feature = "usbtower")]
+//! # fn main() -> rcx::Result<()> {
+//! use rcx::{
+//!     lnp::{LnpAddress, LnpSocket},
+//!     tower::{usb::UsbTower, Framing, TowerConfig},
+//! };
+//!
+//! let config = TowerConfig::new().framing(Framing::Raw);
+//! let tower =
+//!     UsbTower::open_with_config("/dev/usb/legousbtower0", config)?;
+//! let mut socket = LnpSocket::new(tower, LnpAddress::new(0, 1)?)?;
+//! socket.send_to(LnpAddress::new(8, 1)?, b"hello")?;
+//! println!("{:?}", socket.recv()?);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    opcodes::Opcode,
+    tower::{Framing, IrTower},
+    Error, Result,
+};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
+    io::{Cursor, Write},
+};
+
+/// First byte of an integrity packet
+pub const INTEGRITY: u8 = 0xf0;
+
+/// First byte of an addressed packet
+pub const ADDRESSED: u8 = 0xf1;
+
+/// Largest value of the length byte
+const MAX_LENGTH: usize = 0xff;
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0xff, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+/// A host and port on the LNP network, each 0-15
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LnpAddress {
+    pub host: u8,
+    pub port: u8,
+}
+
+impl LnpAddress {
+    pub fn new(host: u8, port: u8) -> Result<Self> {
+        if host > 0x0f || port > 0x0f {
+            return Err(Error::InvalidData("LNP host and port must be 0-15"));
+        }
+        Ok(Self { host, port })
+    }
+
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            host: byte >> 4,
+            port: byte & 0x0f,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        (self.host << 4) | self.port
+    }
+}
+
+impl Display for LnpAddress {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LnpPacket {
+    /// Checked for corruption, but broadcast to every listener
+    Integrity(Vec<u8>),
+    Addressed {
+        dest: LnpAddress,
+        src: LnpAddress,
+        data: Vec<u8>,
+    },
+}
+
+impl LnpPacket {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = match self {
+            Self::Integrity(data) => {
+                let mut out = vec![INTEGRITY, 0];
+                out.extend_from_slice(data);
+                out
+            }
+            Self::Addressed { dest, src, data } => {
+                let mut out = vec![ADDRESSED, 0, dest.to_byte(), src.to_byte()];
+                out.extend_from_slice(data);
+                out
+            }
+        };
+        let length = out.len() - 2;
+        if length > MAX_LENGTH {
+            return Err(Error::InvalidData("LNP packet is too long"));
+        }
+        out[1] = length as u8;
+        out.push(checksum(&out));
+        Ok(out)
+    }
+
+    /// Decode every valid packet in `buf`, in order, skipping noise
+    /// between them
+    pub fn decode_all(buf: &[u8]) -> Vec<Self> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            match decode_at(&buf[pos..]) {
+                Some((packet, len)) => {
+                    out.push(packet);
+                    pos += len;
+                }
+                None => pos += 1,
+            }
+        }
+        out
+    }
+}
+
+/// Try to decode a packet starting at the start of `buf`, returning it
+/// and the number of bytes it occupied
+fn decode_at(buf: &[u8]) -> Option<(LnpPacket, usize)> {
+    let (&kind, rest) = buf.split_first()?;
+    let length = usize::from(*rest.first()?);
+    let total = length + 3;
+    if buf.len() < total || checksum(&buf[..total - 1]) != buf[total - 1] {
+        return None;
+    }
+
+    let body = &buf[2..total - 1];
+    let packet = match kind {
+        INTEGRITY => LnpPacket::Integrity(body.to_vec()),
+        ADDRESSED if body.len() >= 2 => LnpPacket::Addressed {
+            dest: LnpAddress::from_byte(body[0]),
+            src: LnpAddress::from_byte(body[1]),
+            data: body[2..].to_vec(),
+        },
+        _ => return None,
+    };
+    Some((packet, total))
+}
+
+/// An encoded LNP packet, handed to the tower as a request with no reply
+#[derive(Debug)]
+struct Frame(Vec<u8>);
+
+impl Display for Frame {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "LNP {}", hex::encode(&self.0))
+    }
+}
+
+impl Opcode for Frame {
+    type Response = ();
+
+    fn request_opcode(&self) -> u8 {
+        self.0[0]
+    }
+
+    fn response_opcode(&self) -> Option<u8> {
+        None
+    }
+
+    fn parse_response(_reply: &[u8]) -> Result<Self::Response> {
+        Ok(())
+    }
+
+    fn supports_alternate(&self) -> bool {
+        false
+    }
+
+    fn serialise(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut cursor = Cursor::new(buf);
+        cursor.write_all(&self.0[1..])?;
+        Ok(cursor.position().try_into()?)
+    }
+
+    fn disasm(_bin: &[u8], _pc: &mut usize) -> Result<Self> {
+        Err(Error::Parse("LNP packets are not bytecode"))
+    }
+}
+
+/// Sends and receives LNP packets through a tower, as the given address
+pub struct LnpSocket {
+    tower: Box<dyn IrTower>,
+    address: LnpAddress,
+    pending: VecDeque<LnpPacket>,
+}
+
+impl LnpSocket {
+    /// Use `tower`, which must use [`Framing::Raw`], as `address`
+    pub fn new(
+        tower: impl IrTower + 'static,
+        address: LnpAddress,
+    ) -> Result<Self> {
+        if tower.config().framing != Framing::Raw {
+            return Err(Error::InvalidData("The tower must use raw framing"));
+        }
+        Ok(Self {
+            tower: Box::new(tower),
+            address,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn address(&self) -> LnpAddress {
+        self.address
+    }
+
+    fn send(&mut self, packet: &LnpPacket) -> Result<()> {
+        self.tower.send(&Frame(packet.encode()?))?;
+        Ok(())
+    }
+
+    /// Broadcast `data` in an integrity packet
+    pub fn send_integrity(&mut self, data: &[u8]) -> Result<()> {
+        self.send(&LnpPacket::Integrity(data.to_vec()))
+    }
+
+    /// Send `data` in an addressed packet from this socket's address
+    pub fn send_to(&mut self, dest: LnpAddress, data: &[u8]) -> Result<()> {
+        self.send(&LnpPacket::Addressed {
+            dest,
+            src: self.address,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Wait for the next integrity packet or packet addressed to this
+    /// socket. Addressed packets for anyone else are dropped. Returns
+    /// [`Error::Timeout`] if nothing arrives within the tower's read
+    /// timeout.
+    pub fn recv(&mut self) -> Result<LnpPacket> {
+        loop {
+            if let Some(packet) = self.pending.pop_front() {
+                return Ok(packet);
+            }
+            let buf = self.tower.recv()?;
+            let address = self.address;
+            self.pending.extend(
+                LnpPacket::decode_all(&buf).into_iter().filter(|packet| {
+                    match packet {
+                        LnpPacket::Integrity(_) => true,
+                        LnpPacket::Addressed { dest, .. } => *dest == address,
+                    }
+                }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tower::{
+        mock::{MockTower, Reply},
+        TowerConfig,
+    };
+
+    fn address(host: u8, port: u8) -> LnpAddress {
+        LnpAddress::new(host, port).unwrap()
+    }
+
+    /// A tower which hears back whatever it sends
+    fn loopback() -> MockTower {
+        MockTower::new()
+            .with_config(TowerConfig::new().framing(Framing::Raw))
+            .with_responder(|sent| Reply::Frame(sent.frame.clone()))
+    }
+
+    #[test]
+    fn codec() {
+        let integrity = LnpPacket::Integrity(b"hi".to_vec());
+        assert_eq!(integrity.encode().unwrap(), [0xf0, 0x02, 0x68, 0x69, 0xc2]);
+
+        let addressed = LnpPacket::Addressed {
+            dest: address(8, 1),
+            src: address(0, 2),
+            data: vec![0x2a],
+        };
+        let frame = addressed.encode().unwrap();
+        assert_eq!(frame, [0xf1, 0x03, 0x81, 0x02, 0x2a, 0xa0]);
+
+        let mut buf = vec![0x12, 0xf0];
+        buf.extend(integrity.encode().unwrap());
+        buf.extend(&frame);
+        buf.push(0x34);
+        assert_eq!(LnpPacket::decode_all(&buf), [integrity, addressed]);
+
+        let mut bad = frame;
+        bad[4] ^= 0x01;
+        assert!(LnpPacket::decode_all(&bad).is_empty());
+
+        assert!(LnpPacket::Integrity(vec![0; 256]).encode().is_err());
+        assert!(LnpAddress::new(16, 0).is_err());
+    }
+
+    #[test]
+    fn socket_loopback() {
+        let tower = loopback();
+        let mut socket = LnpSocket::new(tower.clone(), address(0, 1)).unwrap();
+
+        socket.send_integrity(b"ping").unwrap();
+        assert_eq!(tower.sent()[0].frame[..2], [0xf0, 0x04]);
+        assert_eq!(
+            socket.recv().unwrap(),
+            LnpPacket::Integrity(b"ping".to_vec())
+        );
+
+        socket.send_to(address(0, 1), b"me").unwrap();
+        assert_eq!(
+            socket.recv().unwrap(),
+            LnpPacket::Addressed {
+                dest: address(0, 1),
+                src: address(0, 1),
+                data: b"me".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn packets_for_others_are_dropped() {
+        let tower = MockTower::new()
+            .with_config(TowerConfig::new().framing(Framing::Raw));
+        let mut socket = LnpSocket::new(tower.clone(), address(0, 1)).unwrap();
+
+        let other = LnpPacket::Addressed {
+            dest: address(0, 2),
+            src: address(8, 1),
+            data: vec![1],
+        };
+        let ours = LnpPacket::Addressed {
+            dest: address(0, 1),
+            src: address(8, 1),
+            data: vec![2],
+        };
+        tower.push_frame(other.encode().unwrap());
+        tower.push_frame(ours.encode().unwrap());
+        tower.push(Reply::Timeout);
+
+        assert_eq!(socket.recv().unwrap(), ours);
+        assert!(matches!(socket.recv(), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn needs_raw_framing() {
+        assert!(LnpSocket::new(MockTower::new(), address(0, 1)).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Decoding of the program memory map returned by `GetMemoryMap`
+//!
+//! The brick keeps every task and subroutine, then the datalog, in one
+//! contiguous region, in the order the map lists them. The size of each
+//! is therefore the distance from its start address to the next one; an
+//! undefined task or subroutine takes up no space.
+
+use crate::opcodes::GetMemoryMapResponse;
+use nqc::binfmt::{RcxBin, SectionType};
+
+/// Number of program slots on the brick
+pub const PROGRAM_SLOTS: usize = 5;
+/// Number of subroutines in each program
+pub const SUBROUTINES: usize = 8;
+/// Number of tasks in each program
+pub const TASKS: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryMap {
+    /// Start addresses of the subroutines of each program slot
+    pub subroutines: [[u16; SUBROUTINES]; PROGRAM_SLOTS],
+    /// Start addresses of the tasks of each program slot
+    pub tasks: [[u16; TASKS]; PROGRAM_SLOTS],
+    /// Start of the space allocated to the datalog
+    pub datalog_start: u16,
+    /// Address of the next datalog entry to be written
+    pub datalog_next: u16,
+    /// Start of the free memory, following the datalog
+    pub free_start: u16,
+    /// Last address available to programs
+    pub last_address: u16,
+}
+
+impl From<GetMemoryMapResponse> for MemoryMap {
+    fn from(resp: GetMemoryMapResponse) -> Self {
+        // The map is sent big-endian, but read as little-endian words
+        let swap = |addr: u16| addr.swap_bytes();
+        Self {
+            subroutines: resp.subroutine_addresses.map(|slot| slot.map(swap)),
+            tasks: resp.task_addresses.map(|slot| slot.map(swap)),
+            datalog_start: swap(resp.datalog_addresses[0]),
+            datalog_next: swap(resp.datalog_addresses[1]),
+            free_start: swap(resp.first_free_address),
+            last_address: swap(resp.last_valid_address),
+        }
+    }
+}
+
+impl MemoryMap {
+    /// Start addresses of every task and subroutine in map order,
+    /// followed by the start of the datalog
+    fn starts(&self) -> impl Iterator<Item = u16> + '_ {
+        self.subroutines
+            .iter()
+            .flatten()
+            .chain(self.tasks.iter().flatten())
+            .copied()
+            .chain([self.datalog_start])
+    }
+
+    /// Size of the chunk at `index` in map order
+    fn chunk_size(&self, index: usize) -> usize {
+        let mut starts = self.starts().skip(index);
+        match (starts.next(), starts.next()) {
+            (Some(start), Some(end)) => end.saturating_sub(start).into(),
+            _ => 0,
+        }
+    }
+
+    /// Size in bytes of a subroutine.
+    ///
+    /// # Panics
+    /// If the slot or subroutine is out of range
+    pub fn subroutine_size(&self, slot: usize, subroutine: usize) -> usize {
+        assert!(slot < PROGRAM_SLOTS && subroutine < SUBROUTINES);
+        self.chunk_size(slot * SUBROUTINES + subroutine)
+    }
+
+    /// Size in bytes of a task.
+    ///
+    /// # Panics
+    /// If the slot or task is out of range
+    pub fn task_size(&self, slot: usize, task: usize) -> usize {
+        assert!(slot < PROGRAM_SLOTS && task < TASKS);
+        self.chunk_size(PROGRAM_SLOTS * SUBROUTINES + slot * TASKS + task)
+    }
+
+    /// Total size in bytes of the tasks and subroutines in a program slot.
+    ///
+    /// # Panics
+    /// If the slot is out of range
+    pub fn program_size(&self, slot: usize) -> usize {
+        (0..SUBROUTINES)
+            .map(|sub| self.subroutine_size(slot, sub))
+            .chain((0..TASKS).map(|task| self.task_size(slot, task)))
+            .sum()
+    }
+
+    /// Bytes allocated to the datalog, whether used or not
+    pub fn datalog_size(&self) -> usize {
+        self.free_start.saturating_sub(self.datalog_start).into()
+    }
+
+    /// Bytes not allocated to any program or the datalog
+    pub fn free(&self) -> usize {
+        (usize::from(self.last_address) + 1)
+            .saturating_sub(self.free_start.into())
+    }
+
+    /// Whether `bin` fits into `slot`, taking into account that
+    /// [`crate::Rcx::download_program`] first deletes the program
+    /// currently in the slot.
+    ///
+    /// # Panics
+    /// If the slot is out of range
+    pub fn fits(&self, slot: usize, bin: &RcxBin) -> bool {
+        let needed: usize = bin
+            .sections
+            .iter()
+            .filter(|section| {
+                matches!(
+                    section.ty,
+                    SectionType::Task | SectionType::Subroutine
+                )
+            })
+            .map(|section| section.data.len())
+            .sum();
+        needed <= self.free() + self.program_size(slot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{bin, section};
+    use nqc::binfmt::TargetType;
+
+    /// Program 0 with a 16 byte subroutine 0 and a 32 byte task 0,
+    /// program 1 with a 16 byte task 0 and a 16 byte datalog
+    fn map_bytes() -> Vec<u8> {
+        let mut addrs = vec![0x0100];
+        addrs.extend([0x0110; 39]);
+        addrs.push(0x0110);
+        addrs.extend([0x0130; 9]);
+        addrs.push(0x0130);
+        addrs.extend([0x0140; 39]);
+        addrs.extend([0x0140, 0x0146, 0x0150, 0x01ff]);
+        addrs
+            .iter()
+            .flat_map(|addr: &u16| addr.to_be_bytes())
+            .collect()
+    }
+
+    /// A task of `len` bytes and a sound, which takes no program space
+    fn program(len: usize) -> RcxBin {
+        bin(
+            TargetType::Rcx,
+            vec![
+                section(SectionType::Task, 0, len),
+                section(SectionType::Sound, 0, 100),
+            ],
+        )
+    }
+
+    #[test]
+    fn sizes() {
+        let map = MemoryMap::from(
+            GetMemoryMapResponse::deserialise(
+                &nqc::packet::Packet::new(0xd7, map_bytes()).encode(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(map.subroutines[0][0], 0x0100);
+        assert_eq!(map.tasks[1][0], 0x0130);
+        assert_eq!(map.last_address, 0x01ff);
+
+        assert_eq!(map.subroutine_size(0, 0), 16);
+        assert_eq!(map.task_size(0, 0), 32);
+        assert_eq!(map.task_size(0, 1), 0);
+        assert_eq!(map.program_size(0), 48);
+        assert_eq!(map.program_size(1), 16);
+        assert_eq!(map.program_size(4), 0);
+        assert_eq!(map.datalog_size(), 16);
+        assert_eq!(map.free(), 176);
+
+        assert!(map.fits(4, &program(176)));
+        assert!(!map.fits(4, &program(177)));
+        assert!(map.fits(0, &program(224)));
+    }
+}
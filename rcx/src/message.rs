@@ -0,0 +1,240 @@
+//! Listening for messages broadcast by other bricks
+//!
+//! Bricks exchange single byte messages by broadcasting a `SetMessage`
+//! request, for example from NQC's `SendMessage`. Nobody acknowledges
+//! them, so senders commonly repeat each message a few times to make
+//! sure it arrives. [`MessageDecoder`] picks these requests out of
+//! received data and drops the repeats.
+//!
+//! ```no_run
+//! # #[cfg(not(feature = "usbtower"))]
+//! # fn main() {}
+//! # #[cfg(feature = "usbtower")]
+//! # fn main() -> rcx::Result<()> {
+//! use rcx::{tower::usb::UsbTower, Rcx};
+//!
+//! let mut rcx = Rcx::new(UsbTower::open("/dev/usb/legousbtower0")?);
+//! for message in rcx.messages() {
+//!     let message = message?;
+//!     println!("Received {message}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    opcodes::{self, Opcode},
+    tower::IrTower,
+    Error, Result,
+};
+use nqc::packet::Packet;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Default time within which a message with the same value as the one
+/// before is treated as a repeat
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_millis(250);
+
+/// Extracts messages from received data, dropping repeats.
+///
+/// A message is a repeat if it has the same value as the previous one
+/// and arrives within the dedup window of it. The window restarts with
+/// every repeat, so a message sent continuously is only reported once.
+#[derive(Clone, Debug)]
+pub struct MessageDecoder {
+    window: Duration,
+    last: Option<(u8, Instant)>,
+}
+
+impl Default for MessageDecoder {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_DEDUP_WINDOW,
+            last: None,
+        }
+    }
+}
+
+impl MessageDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the dedup window. A zero window reports every message.
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Decode the messages in `buf`, which was received at `now`
+    pub fn decode(&mut self, buf: &[u8], now: Instant) -> Vec<u8> {
+        // SetMessage does not support the alternate form, so this is the
+        // only opcode it is sent with
+        let set_message = opcodes::SetMessage { message: 0 }.request_opcode();
+        let mut messages = Vec::new();
+        for packet in Packet::decode_all(buf) {
+            let [message] = packet.payload[..] else {
+                continue;
+            };
+            if packet.opcode != set_message {
+                continue;
+            }
+
+            let repeat = self.last.is_some_and(|(last, at)| {
+                last == message && now.duration_since(at) < self.window
+            });
+            self.last = Some((message, now));
+            if !repeat {
+                messages.push(message);
+            }
+        }
+        messages
+    }
+}
+
+/// Iterator over messages received through a tower, returned by
+/// [`crate::Rcx::messages`].
+///
+/// Waits indefinitely for the next message: timeouts of the tower are
+/// ignored, while other errors are returned.
+pub struct Messages<'a> {
+    tower: &'a mut dyn IrTower,
+    decoder: MessageDecoder,
+    pending: VecDeque<u8>,
+}
+
+impl<'a> Messages<'a> {
+    pub(crate) fn new(tower: &'a mut dyn IrTower) -> Self {
+        Self {
+            tower,
+            decoder: MessageDecoder::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Set the dedup window, see [`MessageDecoder`]
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.decoder = self.decoder.dedup_window(window);
+        self
+    }
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            match self.tower.recv() {
+                Ok(buf) => self
+                    .pending
+                    .extend(self.decoder.decode(&buf, Instant::now())),
+                Err(Error::Timeout) => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+/// Async counterpart of [`Messages`], returned by
+/// [`crate::AsyncRcx::messages`]
+#[cfg(feature = "tokio")]
+pub struct AsyncMessages<'a, T> {
+    tower: &'a mut T,
+    decoder: MessageDecoder,
+    pending: VecDeque<u8>,
+}
+
+#[cfg(feature = "tokio")]
+impl<'a, T: crate::tower::AsyncIrTower> AsyncMessages<'a, T> {
+    pub(crate) fn new(tower: &'a mut T) -> Self {
+        Self {
+            tower,
+            decoder: MessageDecoder::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Set the dedup window, see [`MessageDecoder`]
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.decoder = self.decoder.dedup_window(window);
+        self
+    }
+
+    /// Wait for the next message. Cancellation safe, as long as the
+    /// tower's `recv` is.
+    pub async fn next(&mut self) -> Result<u8> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(message);
+            }
+            match self.tower.recv().await {
+                Ok(buf) => self
+                    .pending
+                    .extend(self.decoder.decode(&buf, Instant::now())),
+                Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        opcodes,
+        tower::mock::{MockTower, Reply},
+        Rcx,
+    };
+
+    fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        Packet::new(opcode, payload.to_vec()).encode()
+    }
+
+    #[test]
+    fn decode() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut decoder = MessageDecoder::new();
+
+        // repeats in one read, with a reply to something else between
+        let buf = [
+            frame(0xf7, &[1]),
+            frame(0xe7, &[]),
+            frame(0xf7, &[1]),
+            frame(0xf7, &[2]),
+        ]
+        .concat();
+        assert_eq!(decoder.decode(&buf, at(0)), [1, 2]);
+        // a repeat extends the window
+        assert_eq!(decoder.decode(&frame(0xf7, &[2]), at(200)), []);
+        assert_eq!(decoder.decode(&frame(0xf7, &[2]), at(400)), []);
+        assert_eq!(decoder.decode(&frame(0xf7, &[2]), at(700)), [2]);
+        assert_eq!(decoder.decode(&frame(0xf7, &[]), at(800)), []);
+
+        let mut decoder = decoder.dedup_window(Duration::ZERO);
+        assert_eq!(decoder.decode(&frame(0xf7, &[2]), at(700)), [2]);
+    }
+
+    #[test]
+    fn listen() {
+        let tower = MockTower::new();
+        tower.push_frame([frame(0xf7, &[5]), frame(0xf7, &[5])].concat());
+        tower.push(Reply::Timeout);
+        tower.push_frame(frame(0xf7, &[6]));
+        tower.push_frame(vec![0x55, 0xff]);
+        tower.push_frame(frame(0xf7, &[5]));
+
+        let mut rcx = Rcx::new(tower.clone());
+        let messages =
+            rcx.messages().take(3).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(messages, [5, 6, 5]);
+
+        // the host can join in
+        rcx.set_message(7).unwrap();
+        tower.assert_sent(&[&opcodes::SetMessage { message: 7 }]);
+    }
+}
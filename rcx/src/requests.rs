@@ -0,0 +1,543 @@
+//! The parts of each request which do not depend on how it is sent,
+//! shared by [`Rcx`](crate::Rcx) and `AsyncRcx`: argument checks,
+//! splitting downloads into blocks and mapping reply codes to errors.
+
+use crate::{
+    datalog::{self, DatalogEntry},
+    opcodes, Error, MotorDirection, MotorDirectionCode, MotorOnOffCode,
+    MotorPowerState, MotorSelection, Result, SensorMode, SensorModeCode,
+    SensorType, SourceType,
+};
+use nqc::{
+    binfmt::{RcxBin, Section, SectionType},
+    srec,
+};
+
+/// Maximum number of bytes carried by a single `TransferData` block
+/// when downloading a program
+const PROGRAM_BLOCK_SIZE: usize = 20;
+
+/// Maximum number of bytes carried by a single `TransferData` block
+/// when downloading firmware
+const FIRMWARE_BLOCK_SIZE: usize = 200;
+
+/// Address the RCX ROM loads firmware to
+const FIRMWARE_START: u32 = 0x8000;
+
+/// Number of times a firmware block is retransmitted after the brick
+/// reports a block checksum failure
+const FIRMWARE_BLOCK_RETRIES: usize = 5;
+
+/// Maximum number of datalog entries requested in a single
+/// `UploadDatalog`, limited by the size of the brick's transmit buffer
+const DATALOG_UPLOAD_CHUNK: i16 = 50;
+
+/// Key which must accompany firmware deletion and version requests
+const KEY: [u8; 5] = [1, 3, 5, 7, 11];
+
+/// Map the error code replied to a download request to an error. The
+/// codes are shared by all download requests.
+pub(crate) fn check_download(errorcode: u8) -> Result<()> {
+    Err(match errorcode {
+        0 => return Ok(()),
+        1 => Error::OutOfMemory,
+        2 => Error::IllegalIndex,
+        3 => Error::BlockChecksum,
+        4 => Error::FirmwareChecksum,
+        6 => Error::NoDownloadInProgress,
+        code => Error::UnknownDownloadError(code),
+    })
+}
+
+/// Check that only motors A and B are selected, on bricks with two
+/// outputs
+pub(crate) fn check_two_motors(motors: MotorSelection) -> Result<()> {
+    if motors.bitfield & !(MotorSelection::A | MotorSelection::B).bitfield != 0
+    {
+        return Err(Error::InvalidData("This brick only has motors A and B"));
+    }
+    Ok(())
+}
+
+fn block(index: i16, data: &[u8]) -> Result<opcodes::TransferData> {
+    Ok(opcodes::TransferData {
+        index,
+        length: i16::try_from(data.len())?,
+        data: data.to_vec(),
+        checksum: data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)),
+    })
+}
+
+pub(crate) fn datalog_next(
+    source: SourceType,
+    argument: u8,
+) -> Result<opcodes::DatalogNext> {
+    if !matches!(
+        source,
+        SourceType::Variable
+            | SourceType::Timer
+            | SourceType::SensorValue
+            | SourceType::Clock
+    ) {
+        return Err(Error::InvalidData(
+            "Datalog source must be a variable, timer, sensor or clock",
+        ));
+    }
+    Ok(opcodes::DatalogNext { source, argument })
+}
+
+pub(crate) fn check_datalog_next(errorcode: u8) -> Result<()> {
+    if errorcode != 0 {
+        return Err(Error::RcxError("Datalog is full"));
+    }
+    Ok(())
+}
+
+pub(crate) fn delete_firmware() -> opcodes::DeleteFirmware {
+    opcodes::DeleteFirmware { key: KEY }
+}
+
+pub(crate) fn delete_subroutine(
+    subroutine: u8,
+) -> Result<opcodes::DeleteSubroutine> {
+    RCX_LIMITS.check_subroutine(subroutine)?;
+    Ok(opcodes::DeleteSubroutine { subroutine })
+}
+
+pub(crate) fn delete_task(task: u8) -> Result<opcodes::DeleteTask> {
+    RCX_LIMITS.check_task(task)?;
+    Ok(opcodes::DeleteTask { task })
+}
+
+/// How many tasks and subroutines a brick holds, and how it expects
+/// program downloads to be split into blocks
+pub(crate) struct ProgramLimits {
+    pub(crate) tasks: u8,
+    pub(crate) subroutines: u8,
+    pub(crate) task_error: &'static str,
+    pub(crate) subroutine_error: &'static str,
+    /// Maximum number of bytes carried by a single `TransferData` block
+    pub(crate) block_size: usize,
+    /// Whether the final block keeps its number instead of being sent
+    /// as block 0
+    pub(crate) number_last_block: bool,
+}
+
+impl ProgramLimits {
+    pub(crate) fn check_task(&self, task: u8) -> Result<()> {
+        if task >= self.tasks {
+            return Err(Error::InvalidData(self.task_error));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_subroutine(&self, subroutine: u8) -> Result<()> {
+        if subroutine >= self.subroutines {
+            return Err(Error::InvalidData(self.subroutine_error));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) const RCX_LIMITS: ProgramLimits = ProgramLimits {
+    tasks: 10,
+    subroutines: 8,
+    task_error: "Task must be 0-9",
+    subroutine_error: "Subroutine must be 0-7",
+    block_size: PROGRAM_BLOCK_SIZE,
+    number_last_block: false,
+};
+
+/// The request which allocates space for a task or subroutine section,
+/// followed by the `TransferData` blocks carrying it. Blocks are
+/// numbered from 1; unless `limits` says otherwise, the final block is
+/// always number 0.
+fn download_section(
+    section: &Section,
+    limits: &ProgramLimits,
+) -> Result<(SectionDownload, Vec<opcodes::TransferData>)> {
+    let length = i16::try_from(section.data.len())?;
+    let start = match section.ty {
+        SectionType::Task => {
+            limits.check_task(section.number)?;
+            SectionDownload::Task(start_task_download(section.number, length)?)
+        }
+        SectionType::Subroutine => {
+            limits.check_subroutine(section.number)?;
+            SectionDownload::Subroutine(start_subroutine_download(
+                section.number,
+                length,
+            )?)
+        }
+        _ => {
+            return Err(Error::InvalidData(
+                "Only task and subroutine sections can be downloaded",
+            ));
+        }
+    };
+
+    let count = section.data.len().div_ceil(limits.block_size);
+    let blocks = section
+        .data
+        .chunks(limits.block_size)
+        .enumerate()
+        .map(|(idx, data)| {
+            let index = if idx + 1 == count && !limits.number_last_block {
+                0
+            } else {
+                i16::try_from(idx + 1)?
+            };
+            block(index, data)
+        })
+        .collect::<Result<_>>()?;
+    Ok((start, blocks))
+}
+
+/// The request starting a section download
+pub(crate) enum SectionDownload {
+    Task(opcodes::StartTaskDownload),
+    Subroutine(opcodes::StartSubroutineDownload),
+}
+
+/// A section of a program together with the requests which download
+/// it
+pub(crate) struct SectionPlan<'a> {
+    pub(crate) section: &'a Section,
+    pub(crate) start: SectionDownload,
+    pub(crate) blocks: Vec<opcodes::TransferData>,
+}
+
+/// Check every section of `bin` against `limits` and split it into
+/// requests, so that a program which cannot be downloaded is rejected
+/// before anything on the brick is deleted
+pub(crate) fn plan_program<'a>(
+    bin: &'a RcxBin,
+    limits: &ProgramLimits,
+) -> Result<Vec<SectionPlan<'a>>> {
+    bin.sections
+        .iter()
+        .map(|section| {
+            let (start, blocks) = download_section(section, limits)
+                .map_err(|source| section_error(section, source))?;
+            Ok(SectionPlan {
+                section,
+                start,
+                blocks,
+            })
+        })
+        .collect()
+}
+
+/// Attribute an error to the section being downloaded
+pub(crate) fn section_error(section: &Section, source: Error) -> Error {
+    Error::SectionDownload {
+        ty: section.ty,
+        number: section.number,
+        source: Box::new(source),
+    }
+}
+
+/// Tracks which firmware block is to be sent next and how it is
+/// numbered, including retransmissions after checksum failures
+pub(crate) struct FirmwareDownload<'a> {
+    blocks: std::slice::Chunks<'a, u8>,
+    current: Option<&'a [u8]>,
+    sequence: i16,
+    retries: usize,
+    sent: usize,
+    total: usize,
+}
+
+impl<'a> FirmwareDownload<'a> {
+    /// Check the image can be downloaded, returning the request which
+    /// starts the download and the plan for its blocks
+    pub(crate) fn new(
+        image: &'a srec::Image,
+    ) -> Result<(opcodes::StartFirmwareDownload, Self)> {
+        if image.start != FIRMWARE_START {
+            return Err(Error::InvalidData(
+                "Firmware image must start at address 0x8000",
+            ));
+        }
+        let entry = u16::try_from(image.entry)?;
+        let start = opcodes::StartFirmwareDownload {
+            address: entry as i16,
+            checksum: image.checksum() as i16,
+            unknown: 0,
+        };
+
+        let mut blocks = image.data.chunks(FIRMWARE_BLOCK_SIZE);
+        let download = Self {
+            current: blocks.next(),
+            blocks,
+            sequence: 1,
+            retries: 0,
+            sent: 0,
+            total: image.data.len(),
+        };
+        Ok((start, download))
+    }
+
+    fn is_last(&self) -> bool {
+        self.blocks.len() == 0
+    }
+
+    /// The next block to send, or `None` once every block has been
+    /// acknowledged
+    pub(crate) fn next_block(&self) -> Option<Result<opcodes::TransferData>> {
+        let index = if self.is_last() { 0 } else { self.sequence };
+        self.current.map(|data| block(index, data))
+    }
+
+    /// Record the result of sending the block from [`Self::next_block`].
+    /// Returns the number of bytes sent so far and the image size once
+    /// the block is acknowledged, or `None` if it must be sent again.
+    pub(crate) fn complete(
+        &mut self,
+        result: Result<()>,
+    ) -> Result<Option<(usize, usize)>> {
+        // A bug in the ROM means that a block retransmitted after a
+        // checksum failure must use the next sequence number
+        self.sequence += 1;
+        match result {
+            Ok(()) => {
+                self.sent += self.current.map_or(0, <[u8]>::len);
+                self.current = self.blocks.next();
+                self.retries = 0;
+                Ok(Some((self.sent, self.total)))
+            }
+            Err(Error::BlockChecksum)
+                if !self.is_last() && self.retries < FIRMWARE_BLOCK_RETRIES =>
+            {
+                self.retries += 1;
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+pub(crate) fn get_value(
+    source: SourceType,
+    argument: u8,
+) -> Result<opcodes::GetValue> {
+    if matches!(source, SourceType::Immediate | SourceType::Random) {
+        return Err(Error::InvalidData(
+            "Immediate and random sources cannot be read",
+        ));
+    }
+    Ok(opcodes::GetValue { source, argument })
+}
+
+pub(crate) fn get_versions() -> opcodes::GetVersions {
+    opcodes::GetVersions { key: KEY }
+}
+
+/// Index of the motor whose state is read by `GetValue`
+pub(crate) fn motor_index(motor: MotorSelection) -> Result<u8> {
+    match motor {
+        MotorSelection::A => Ok(0),
+        MotorSelection::B => Ok(1),
+        MotorSelection::C => Ok(2),
+        _ => Err(Error::InvalidData("Exactly one motor must be selected")),
+    }
+}
+
+pub(crate) fn read_sensor(
+    source: SourceType,
+    sensor: u8,
+) -> Result<opcodes::GetValue> {
+    if sensor > 2 {
+        return Err(Error::InvalidData("Sensor must be 0-2"));
+    }
+    get_value(source, sensor)
+}
+
+pub(crate) fn read_timer(timer: u8) -> Result<opcodes::GetValue> {
+    if timer > 3 {
+        return Err(Error::InvalidData("Timer must be 0-3"));
+    }
+    get_value(SourceType::Timer, timer)
+}
+
+pub(crate) fn read_variable(variable: u8) -> Result<opcodes::GetValue> {
+    if variable > 31 {
+        return Err(Error::InvalidData("Variable must be 0-31"));
+    }
+    get_value(SourceType::Variable, variable)
+}
+
+pub(crate) fn set_datalog_size(size: i16) -> Result<opcodes::SetDatalogSize> {
+    if size < 0 {
+        return Err(Error::InvalidData("Datalog size must be positive"));
+    }
+    Ok(opcodes::SetDatalogSize { size })
+}
+
+pub(crate) fn check_set_datalog_size(errorcode: u8) -> Result<()> {
+    if errorcode != 0 {
+        return Err(Error::RcxError("Insufficient memory for datalog"));
+    }
+    Ok(())
+}
+
+pub(crate) fn set_motor_direction(
+    motor: MotorSelection,
+    direction: MotorDirection,
+) -> opcodes::SetMotorDirection {
+    opcodes::SetMotorDirection {
+        code: MotorDirectionCode {
+            motors: motor,
+            direction: direction.into(),
+        },
+    }
+}
+
+pub(crate) fn set_motor_on_off(
+    motor: MotorSelection,
+    state: MotorPowerState,
+) -> opcodes::SetMotorOnOff {
+    opcodes::SetMotorOnOff {
+        code: MotorOnOffCode {
+            motors: motor,
+            state,
+        },
+    }
+}
+
+pub(crate) fn set_motor_power(
+    motor: MotorSelection,
+    power: u8,
+) -> Result<opcodes::SetMotorPower> {
+    if power > 7 {
+        return Err(Error::InvalidData("Motor power must be 0-7"));
+    }
+    Ok(opcodes::SetMotorPower {
+        motors: motor,
+        source: SourceType::Immediate,
+        argument: power,
+    })
+}
+
+pub(crate) fn set_program_number(
+    program: u8,
+) -> Result<opcodes::SetProgramNumber> {
+    if program > 4 {
+        return Err(Error::InvalidData("Program number must be 0-4"));
+    }
+    Ok(opcodes::SetProgramNumber { program })
+}
+
+pub(crate) fn set_sensor_mode(
+    sensor: u8,
+    mode: SensorMode,
+) -> Result<opcodes::SetSensorMode> {
+    if sensor > 2 {
+        return Err(Error::InvalidData("Sensor index must be 0-2"));
+    }
+    Ok(opcodes::SetSensorMode {
+        sensor,
+        code: SensorModeCode { mode, slope: 0 },
+    })
+}
+
+pub(crate) fn set_sensor_type(
+    sensor: u8,
+    ty: SensorType,
+) -> Result<opcodes::SetSensorType> {
+    if sensor > 2 {
+        return Err(Error::InvalidData("Sensor index must be 0-2"));
+    }
+    Ok(opcodes::SetSensorType { sensor, type_: ty })
+}
+
+pub(crate) fn set_time(hours: u8, minutes: u8) -> Result<opcodes::SetTime> {
+    if hours > 23 || minutes > 59 {
+        return Err(Error::InvalidData(
+            "Hours must be 0-23 and minutes must be 0-59",
+        ));
+    }
+    Ok(opcodes::SetTime { hours, minutes })
+}
+
+pub(crate) fn start_subroutine_download(
+    subroutine: u8,
+    length: i16,
+) -> Result<opcodes::StartSubroutineDownload> {
+    RCX_LIMITS.check_subroutine(subroutine)?;
+    Ok(opcodes::StartSubroutineDownload {
+        reserved: 0,
+        subroutine,
+        reserved2: 0,
+        length,
+    })
+}
+
+pub(crate) fn start_task(task: u8) -> Result<opcodes::StartTask> {
+    RCX_LIMITS.check_task(task)?;
+    Ok(opcodes::StartTask { task })
+}
+
+pub(crate) fn start_task_download(
+    task: u8,
+    length: i16,
+) -> Result<opcodes::StartTaskDownload> {
+    RCX_LIMITS.check_task(task)?;
+    Ok(opcodes::StartTaskDownload {
+        reserved: 0,
+        task,
+        reserved2: 0,
+        length,
+    })
+}
+
+pub(crate) fn stop_task(task: u8) -> Result<opcodes::StopTask> {
+    RCX_LIMITS.check_task(task)?;
+    Ok(opcodes::StopTask { task })
+}
+
+pub(crate) fn unlock_firmware() -> opcodes::UnlockFirmware {
+    opcodes::UnlockFirmware { key: *b"LEGO\xae" }
+}
+
+pub(crate) fn check_unlock_firmware(
+    resp: &opcodes::UnlockFirmwareResponse,
+) -> Result<()> {
+    if &resp.data == b"Just a bit off the block!" {
+        Ok(())
+    } else {
+        Err(Error::RcxError("Unexpected response from brick"))
+    }
+}
+
+/// Number of entries in the datalog, including the size entry, from the
+/// first entry of the datalog
+pub(crate) fn datalog_size(first: &[DatalogEntry]) -> Result<i16> {
+    match first {
+        [DatalogEntry {
+            source: datalog::DatalogSource::Size,
+            value,
+        }] => Ok(*value),
+        _ => Err(Error::RcxError("Missing datalog size entry")),
+    }
+}
+
+/// The `(first, count)` ranges fetching every entry after the size
+/// entry of a datalog holding `size` entries
+pub(crate) fn datalog_chunks(size: i16) -> impl Iterator<Item = (i16, i16)> {
+    (1..size)
+        .step_by(DATALOG_UPLOAD_CHUNK as usize)
+        .map(move |first| (first, DATALOG_UPLOAD_CHUNK.min(size - first)))
+}
+
+/// Entries in the reply to an `UploadDatalog` of `count` entries
+pub(crate) fn parse_datalog(
+    resp: &opcodes::UploadDatalogResponse,
+    count: i16,
+) -> Result<Vec<DatalogEntry>> {
+    // the brick replies with no entries if the range is invalid
+    if resp.data.len() != count as usize * datalog::ENTRY_LEN {
+        return Err(Error::RcxError("Datalog upload failed"));
+    }
+    DatalogEntry::parse_all(&resp.data)
+}
@@ -0,0 +1,282 @@
+//! Client for the LEGO Scout, the programmable brick of the Robotics
+//! Discovery Set.
+//!
+//! The Scout understands a subset of the RCX requests, plus some of its
+//! own for its stand-alone rules and built-in light. It has two motors,
+//! six tasks and no datalog or program slots.
+
+use crate::{
+    opcodes, requests, tower::IrTower, Error, MotorDirection, MotorPowerState,
+    MotorSelection, Rcx, Result, ScoutEffect, ScoutLight, ScoutMode,
+    ScoutMotion, ScoutTime, ScoutTouch, Sound, SourceType, TransmitterRange,
+};
+use nqc::binfmt::{RcxBin, TargetType};
+
+/// Tasks and subroutines a Scout program can have
+const SCOUT_LIMITS: requests::ProgramLimits = requests::ProgramLimits {
+    tasks: 6,
+    subroutines: 3,
+    task_error: "Task must be 0-5",
+    subroutine_error: "Subroutine must be 0-2",
+    ..requests::RCX_LIMITS
+};
+
+/// Number of sound sets built into the Scout
+const SCOUT_SOUND_SETS: u8 = 6;
+
+/// Behaviour of the Scout in stand-alone mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScoutRules {
+    pub motion: ScoutMotion,
+    pub touch: ScoutTouch,
+    pub light: ScoutLight,
+    pub time: ScoutTime,
+    pub effect: ScoutEffect,
+}
+
+pub struct Scout {
+    /// Requests common to both bricks are sent through an RCX client,
+    /// which only exposes those the Scout understands
+    rcx: Rcx,
+}
+
+impl Scout {
+    pub fn new(tower: impl IrTower + 'static) -> Self {
+        Self {
+            rcx: Rcx::new(tower),
+        }
+    }
+
+    pub fn alive(&mut self) -> Result<()> {
+        self.rcx.alive()
+    }
+
+    /// Use the current light level as the reference for the light rules
+    pub fn calibrate_light_sensor(&mut self) -> Result<()> {
+        self.rcx.execute(&opcodes::CalibrateSensor {})
+    }
+
+    /// Download a program compiled for the Scout, replacing the current
+    /// one
+    pub fn download_program(&mut self, bin: &RcxBin) -> Result<()> {
+        if bin.target_type != TargetType::Scout {
+            return Err(Error::InvalidData(
+                "Program was not compiled for the Scout",
+            ));
+        }
+        let sections = requests::plan_program(bin, &SCOUT_LIMITS)?;
+        self.rcx.replace_program(&sections)
+    }
+
+    pub fn get_battery_power(
+        &mut self,
+    ) -> Result<opcodes::GetBatteryPowerResponse> {
+        self.rcx.get_battery_power()
+    }
+
+    pub fn get_versions(&mut self) -> Result<opcodes::GetVersionsResponse> {
+        self.rcx.get_versions()
+    }
+
+    pub fn play_sound(&mut self, sound: Sound) -> Result<()> {
+        self.rcx.play_sound(sound)
+    }
+
+    pub fn play_tone(
+        &mut self,
+        frequency_hz: i16,
+        duration_cs: i8,
+    ) -> Result<()> {
+        self.rcx.play_tone(frequency_hz, duration_cs)
+    }
+
+    pub fn power_off(&mut self) -> Result<()> {
+        self.rcx.power_off()
+    }
+
+    /// Select the set of sounds used for system and rule sounds, 0-5
+    pub fn select_sounds(&mut self, group: u8) -> Result<()> {
+        if group >= SCOUT_SOUND_SETS {
+            return Err(Error::InvalidData("Sound set must be 0-5"));
+        }
+        self.rcx.execute(&opcodes::SelectSounds { group })
+    }
+
+    /// Choose which events are acknowledged with a sound, as a bit mask
+    pub fn set_event_feedback(&mut self, events: u16) -> Result<()> {
+        self.rcx.execute(&opcodes::SetEventFeedback {
+            source: SourceType::Immediate,
+            argument: events as i16,
+        })
+    }
+
+    /// Turn the built-in light on or off
+    pub fn set_light(&mut self, on: bool) -> Result<()> {
+        let mode = if on { 0x80 } else { 0x00 };
+        self.rcx.execute(&opcodes::SetLight { mode })
+    }
+
+    pub fn set_mode(&mut self, mode: ScoutMode) -> Result<()> {
+        self.rcx.execute(&opcodes::ScoutMode { mode })
+    }
+
+    pub fn set_motor_direction(
+        &mut self,
+        motor: MotorSelection,
+        direction: MotorDirection,
+    ) -> Result<()> {
+        requests::check_two_motors(motor)?;
+        self.rcx.set_motor_direction(motor, direction)
+    }
+
+    pub fn set_motor_on_off(
+        &mut self,
+        motor: MotorSelection,
+        state: MotorPowerState,
+    ) -> Result<()> {
+        requests::check_two_motors(motor)?;
+        self.rcx.set_motor_on_off(motor, state)
+    }
+
+    pub fn set_motor_power(
+        &mut self,
+        motor: MotorSelection,
+        power: u8,
+    ) -> Result<()> {
+        requests::check_two_motors(motor)?;
+        self.rcx.set_motor_power(motor, power)
+    }
+
+    pub fn set_power_down_delay(&mut self, minutes: u8) -> Result<()> {
+        self.rcx.set_power_down_delay(minutes)
+    }
+
+    /// Set the behaviour in stand-alone mode, as with the buttons and
+    /// selector on the Scout itself
+    pub fn set_rules(&mut self, rules: ScoutRules) -> Result<()> {
+        self.rcx.execute(&opcodes::ScoutRules {
+            motion: rules.motion,
+            touch: rules.touch,
+            light: rules.light,
+            time: rules.time,
+            effect: rules.effect,
+        })
+    }
+
+    pub fn set_transmitter_range(
+        &mut self,
+        range: TransmitterRange,
+    ) -> Result<()> {
+        self.rcx.set_transmitter_range(range)
+    }
+
+    pub fn start_task(&mut self, task: u8) -> Result<()> {
+        SCOUT_LIMITS.check_task(task)?;
+        self.rcx.start_task(task)
+    }
+
+    pub fn stop_all_tasks(&mut self) -> Result<()> {
+        self.rcx.stop_all_tasks()
+    }
+
+    pub fn stop_task(&mut self, task: u8) -> Result<()> {
+        SCOUT_LIMITS.check_task(task)?;
+        self.rcx.stop_task(task)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{bin, section};
+    use crate::tower::mock::MockTower;
+    use nqc::binfmt::SectionType;
+
+    #[test]
+    fn rules() {
+        let tower = MockTower::new();
+        let mut scout = Scout::new(tower.clone());
+        scout.set_mode(ScoutMode::Standalone).unwrap();
+        scout
+            .set_rules(ScoutRules {
+                motion: ScoutMotion::Zigzag,
+                touch: ScoutTouch::Avoid,
+                light: ScoutLight::SeekDark,
+                time: ScoutTime::Long,
+                effect: ScoutEffect::Science,
+            })
+            .unwrap();
+        scout.set_light(true).unwrap();
+
+        tower.assert_sent(&[
+            &opcodes::ScoutMode {
+                mode: ScoutMode::Standalone,
+            },
+            &opcodes::ScoutRules {
+                motion: ScoutMotion::Zigzag,
+                touch: ScoutTouch::Avoid,
+                light: ScoutLight::SeekDark,
+                time: ScoutTime::Long,
+                effect: ScoutEffect::Science,
+            },
+            &opcodes::SetLight { mode: 0x80 },
+        ]);
+        // Scout requests alternate like any other
+        assert_eq!(tower.sent()[1].frame[3], 0xdd);
+    }
+
+    #[test]
+    fn argument_validation() {
+        let tower = MockTower::new();
+        let mut scout = Scout::new(tower.clone());
+        assert!(scout.set_motor_power(MotorSelection::C, 3).is_err());
+        assert!(scout
+            .set_motor_on_off(
+                MotorSelection::A | MotorSelection::C,
+                MotorPowerState::On
+            )
+            .is_err());
+        assert!(scout.start_task(6).is_err());
+        assert!(scout.select_sounds(6).is_err());
+        assert!(tower.sent().is_empty());
+
+        scout
+            .set_motor_on_off(
+                MotorSelection::A | MotorSelection::B,
+                MotorPowerState::On,
+            )
+            .unwrap();
+        scout.start_task(5).unwrap();
+        assert_eq!(tower.sent().len(), 2);
+    }
+
+    #[test]
+    fn download_program() {
+        let mut program = bin(TargetType::Rcx, Vec::new());
+        let tower = MockTower::new();
+        let mut scout = Scout::new(tower.clone());
+        assert!(scout.download_program(&program).is_err());
+
+        program.target_type = TargetType::Scout;
+        scout.download_program(&program).unwrap();
+        tower.assert_sent(&[
+            &opcodes::DeleteAllTasks {},
+            &opcodes::DeleteAllSubroutines {},
+        ]);
+    }
+
+    #[test]
+    fn download_is_checked_first() {
+        let tower = MockTower::new();
+        let mut scout = Scout::new(tower.clone());
+        for (ty, number) in [
+            (SectionType::Task, 6),
+            (SectionType::Subroutine, 3),
+            (SectionType::Sound, 0),
+        ] {
+            let program = bin(TargetType::Scout, vec![section(ty, number, 1)]);
+            assert!(scout.download_program(&program).is_err());
+        }
+        assert!(tower.sent().is_empty());
+    }
+}
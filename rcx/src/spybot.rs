@@ -0,0 +1,207 @@
+//! Client for LEGO Spybotics bricks.
+//!
+//! Spybotics bricks understand the RCX direct commands, but frame their
+//! packets without complement bytes (see [`Framing::Spybotics`]), hold a
+//! single program of up to eight tasks and eight subroutines, and accept
+//! larger download blocks, numbered consecutively to the end.
+
+use crate::{
+    opcodes, requests, tower::IrTower, Error, MotorDirection, MotorPowerState,
+    MotorSelection, Rcx, Result, Sound,
+};
+use nqc::{
+    binfmt::{RcxBin, TargetType},
+    packet::Framing,
+};
+
+/// Tasks and subroutines a Spybotics program can have, and its larger
+/// download blocks which are numbered consecutively to the end
+const SPYBOT_LIMITS: requests::ProgramLimits = requests::ProgramLimits {
+    tasks: 8,
+    subroutines: 8,
+    task_error: "Task must be 0-7",
+    subroutine_error: "Subroutine must be 0-7",
+    block_size: 128,
+    number_last_block: true,
+};
+
+pub struct Spybot {
+    rcx: Rcx,
+}
+
+impl Spybot {
+    /// Talk to a Spybotics brick through `tower`, which must use
+    /// [`Framing::Spybotics`]
+    pub fn new(tower: impl IrTower + 'static) -> Result<Self> {
+        if tower.config().framing != Framing::Spybotics {
+            return Err(Error::InvalidData(
+                "The tower must use Spybotics framing",
+            ));
+        }
+        Ok(Self {
+            rcx: Rcx::new(tower),
+        })
+    }
+
+    pub fn alive(&mut self) -> Result<()> {
+        self.rcx.alive()
+    }
+
+    /// Download a program compiled for Spybotics, replacing the current
+    /// one
+    pub fn download_program(&mut self, bin: &RcxBin) -> Result<()> {
+        if bin.target_type != TargetType::Spybotics {
+            return Err(Error::InvalidData(
+                "Program was not compiled for Spybotics",
+            ));
+        }
+        let sections = requests::plan_program(bin, &SPYBOT_LIMITS)?;
+        self.rcx.replace_program(&sections)
+    }
+
+    pub fn get_battery_power(
+        &mut self,
+    ) -> Result<opcodes::GetBatteryPowerResponse> {
+        self.rcx.get_battery_power()
+    }
+
+    pub fn get_versions(&mut self) -> Result<opcodes::GetVersionsResponse> {
+        self.rcx.get_versions()
+    }
+
+    pub fn play_sound(&mut self, sound: Sound) -> Result<()> {
+        self.rcx.play_sound(sound)
+    }
+
+    pub fn play_tone(
+        &mut self,
+        frequency_hz: i16,
+        duration_cs: i8,
+    ) -> Result<()> {
+        self.rcx.play_tone(frequency_hz, duration_cs)
+    }
+
+    pub fn power_off(&mut self) -> Result<()> {
+        self.rcx.power_off()
+    }
+
+    pub fn read_variable(&mut self, variable: u8) -> Result<i16> {
+        self.rcx.read_variable(variable)
+    }
+
+    pub fn set_message(&mut self, message: u8) -> Result<()> {
+        self.rcx.set_message(message)
+    }
+
+    pub fn set_motor_direction(
+        &mut self,
+        motor: MotorSelection,
+        direction: MotorDirection,
+    ) -> Result<()> {
+        requests::check_two_motors(motor)?;
+        self.rcx.set_motor_direction(motor, direction)
+    }
+
+    pub fn set_motor_on_off(
+        &mut self,
+        motor: MotorSelection,
+        state: MotorPowerState,
+    ) -> Result<()> {
+        requests::check_two_motors(motor)?;
+        self.rcx.set_motor_on_off(motor, state)
+    }
+
+    pub fn set_motor_power(
+        &mut self,
+        motor: MotorSelection,
+        power: u8,
+    ) -> Result<()> {
+        requests::check_two_motors(motor)?;
+        self.rcx.set_motor_power(motor, power)
+    }
+
+    pub fn start_task(&mut self, task: u8) -> Result<()> {
+        SPYBOT_LIMITS.check_task(task)?;
+        self.rcx.start_task(task)
+    }
+
+    pub fn stop_all_tasks(&mut self) -> Result<()> {
+        self.rcx.stop_all_tasks()
+    }
+
+    pub fn stop_task(&mut self, task: u8) -> Result<()> {
+        SPYBOT_LIMITS.check_task(task)?;
+        self.rcx.stop_task(task)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{self, bin, section, transfer};
+    use crate::tower::{mock::MockTower, TowerConfig};
+    use nqc::binfmt::SectionType;
+
+    fn mock() -> MockTower {
+        fixtures::mock()
+            .with_config(TowerConfig::new().framing(Framing::Spybotics))
+    }
+
+    #[test]
+    fn direct_commands() {
+        let tower = mock();
+        let mut spybot = Spybot::new(tower.clone()).unwrap();
+        spybot.alive().unwrap();
+        spybot.start_task(7).unwrap();
+        assert!(spybot.start_task(8).is_err());
+        assert!(spybot.set_motor_power(MotorSelection::C, 3).is_err());
+
+        let sent = tower.sent();
+        assert_eq!(sent.len(), 2);
+        // no complement bytes
+        assert_eq!(sent[0].frame, [0x55, 0xff, 0x00, 0x10, 0xf0]);
+
+        assert!(Spybot::new(MockTower::new()).is_err());
+    }
+
+    #[test]
+    fn download_program() {
+        let tower = mock();
+        let mut spybot = Spybot::new(tower.clone()).unwrap();
+        let program = bin(
+            TargetType::Spybotics,
+            vec![section(SectionType::Task, 0, 200)],
+        );
+        spybot.download_program(&program).unwrap();
+
+        let data = &program.sections[0].data;
+        tower.assert_sent(&[
+            &opcodes::DeleteAllTasks {},
+            &opcodes::DeleteAllSubroutines {},
+            &opcodes::StartTaskDownload {
+                reserved: 0,
+                task: 0,
+                reserved2: 0,
+                length: 200,
+            },
+            &transfer(1, &data[..128]),
+            &transfer(2, &data[128..]),
+        ]);
+    }
+
+    #[test]
+    fn download_is_checked_first() {
+        let tower = mock();
+        let mut spybot = Spybot::new(tower.clone()).unwrap();
+        let mut program = bin(
+            TargetType::Spybotics,
+            vec![section(SectionType::Task, 8, 10)],
+        );
+        assert!(spybot.download_program(&program).is_err());
+
+        program.sections[0].number = 0;
+        program.target_type = TargetType::Rcx;
+        assert!(spybot.download_program(&program).is_err());
+        assert!(tower.sent().is_empty());
+    }
+}
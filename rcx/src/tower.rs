@@ -1,14 +1,394 @@
+pub mod mock;
+pub mod record;
+#[cfg(feature = "serialtower")]
+pub mod serial;
+pub mod tcp;
 #[cfg(feature = "usbtower")]
 pub mod usb;
 
-use crate::{opcodes::Opcode, Result};
+use crate::{opcodes::Opcode, Error, Result, TransmitterRange};
+pub use nqc::packet::Framing;
+use nqc::packet::Packet;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::time::Duration;
+#[cfg(any(feature = "usbtower", feature = "serialtower"))]
+use std::{os::fd::AsFd, time::Instant};
+use tracing::debug;
+
+/// How [`IrTower::send_recv`] retries a request that was not answered
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of times a request is sent, including the first
+    pub attempts: usize,
+    /// Extra delay before each retransmission, on top of the pacing
+    /// enforced by the tower
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::ZERO,
+        }
+    }
+}
+
+/// Timing and behaviour shared by all tower transports.
+///
+/// ```
+/// use rcx::{tower::TowerConfig, TransmitterRange};
+/// use std::time::Duration;
+///
+/// let config = TowerConfig::new()
+///     .read_timeout(Duration::from_millis(500))
+///     .attempts(1)
+///     .range(TransmitterRange::Long);
+/// assert_eq!(config.retry_policy.attempts, 1);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TowerConfig {
+    /// How long to wait for a reply before giving up on an attempt
+    pub read_timeout: Duration,
+    /// Minimum time between the start of successive transmissions, so
+    /// as not to confuse the RCX
+    pub tx_gap: Duration,
+    pub retry_policy: RetryPolicy,
+    /// Whether the tower hears its own transmissions, which then have
+    /// to be removed from the start of each reply. Only used by the
+    /// serial tower; other towers return whatever they receive, and any
+    /// echo in it is skipped when the reply is decoded.
+    pub strip_echo: bool,
+    /// Transmitter range to select on the brick before the first
+    /// request, or `None` to leave it unchanged
+    pub range: Option<TransmitterRange>,
+    /// Header to send in front of each request
+    pub framing: Framing,
+}
+
+impl Default for TowerConfig {
+    fn default() -> Self {
+        Self {
+            read_timeout: Duration::from_secs(5),
+            tx_gap: Duration::from_millis(300),
+            retry_policy: RetryPolicy::default(),
+            strip_echo: true,
+            range: None,
+            framing: Framing::Rcx,
+        }
+    }
+}
+
+impl TowerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    pub fn tx_gap(mut self, tx_gap: Duration) -> Self {
+        self.tx_gap = tx_gap;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set the total number of times a request is sent, keeping the
+    /// current backoff
+    pub fn attempts(mut self, attempts: usize) -> Self {
+        self.retry_policy.attempts = attempts;
+        self
+    }
+
+    pub fn strip_echo(mut self, strip_echo: bool) -> Self {
+        self.strip_echo = strip_echo;
+        self
+    }
+
+    pub fn range(mut self, range: TransmitterRange) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+}
+
+/// Block until `fd` is readable or `deadline` passes. Returns whether
+/// there is data to read.
+#[cfg(any(feature = "usbtower", feature = "serialtower"))]
+pub(crate) fn wait_readable(fd: &impl AsFd, deadline: Instant) -> Result<bool> {
+    use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout =
+            PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(fd.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(ready) => return Ok(ready > 0),
+            // interrupted by a signal before the deadline
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(err) => return Err(std::io::Error::from(err).into()),
+        }
+    }
+}
 
 pub trait IrTower {
-    fn send(&mut self, msg: &dyn Opcode) -> Result<()>;
+    /// Transmit a message. Returns the opcode as transmitted, which
+    /// alternates between the plain and alternate form on successive
+    /// calls.
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8>;
     fn recv(&mut self) -> Result<Vec<u8>>;
 
+    fn config(&self) -> TowerConfig {
+        TowerConfig::default()
+    }
+
+    /// Send a request and wait for the matching reply, returning the
+    /// reply packet. Messages which have no reply are sent once and an
+    /// empty buffer is returned.
+    ///
+    /// The reply must be the complement of the opcode as transmitted;
+    /// anything else, such as the tower's echo of the request or a stale
+    /// reply to an earlier request, is ignored. If no valid reply
+    /// arrives the request is retransmitted, using the other form of its
+    /// opcode, according to the tower's [`RetryPolicy`].
     fn send_recv(&mut self, msg: &dyn Opcode) -> Result<Vec<u8>> {
-        self.send(msg)?;
-        self.recv()
+        if msg.response_opcode().is_none() {
+            self.send(msg)?;
+            return Ok(Vec::new());
+        }
+
+        let policy = self.config().retry_policy;
+        let mut last_err = Error::Timeout;
+        for attempt in 0..policy.attempts.max(1) {
+            if attempt > 0 {
+                debug!("Retrying {msg} ({last_err})");
+                std::thread::sleep(policy.backoff);
+            }
+
+            let opcode = self.send(msg)?;
+            let framing = self.config().framing;
+            match self.recv().and_then(|buf| {
+                Packet::decode_reply_framed(&buf, opcode, framing)
+            }) {
+                Ok(reply) => return Ok(reply.encode()),
+                Err(err) if is_retryable(&err) => last_err = err,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(exhausted(policy, last_err))
+    }
+}
+
+impl<T: IrTower + ?Sized> IrTower for Box<T> {
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8> {
+        (**self).send(msg)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        (**self).recv()
+    }
+
+    fn config(&self) -> TowerConfig {
+        (**self).config()
+    }
+
+    fn send_recv(&mut self, msg: &dyn Opcode) -> Result<Vec<u8>> {
+        (**self).send_recv(msg)
+    }
+}
+
+/// Async counterpart of [`IrTower`], for use with [`crate::AsyncRcx`].
+///
+/// Timeouts are implemented by dropping the pending read, so `recv` must
+/// be cancellation safe: no bytes of a reply may be lost if its future
+/// is dropped before completion.
+#[cfg(feature = "tokio")]
+pub trait AsyncIrTower: Send {
+    /// Transmit a message. Returns the opcode as transmitted, which
+    /// alternates between the plain and alternate form on successive
+    /// calls.
+    fn send(
+        &mut self,
+        msg: &(dyn Opcode + Sync),
+    ) -> impl Future<Output = Result<u8>> + Send;
+    fn recv(&mut self) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    fn config(&self) -> TowerConfig {
+        TowerConfig::default()
+    }
+
+    /// Send a request and wait for the matching reply, retrying as
+    /// described for [`IrTower::send_recv`]
+    fn send_recv(
+        &mut self,
+        msg: &(dyn Opcode + Sync),
+    ) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        async move {
+            if msg.response_opcode().is_none() {
+                self.send(msg).await?;
+                return Ok(Vec::new());
+            }
+
+            let policy = self.config().retry_policy;
+            let mut last_err = Error::Timeout;
+            for attempt in 0..policy.attempts.max(1) {
+                if attempt > 0 {
+                    debug!("Retrying {msg} ({last_err})");
+                    tokio::time::sleep(policy.backoff).await;
+                }
+
+                let opcode = self.send(msg).await?;
+                let framing = self.config().framing;
+                match self.recv().await.and_then(|buf| {
+                    Packet::decode_reply_framed(&buf, opcode, framing)
+                }) {
+                    Ok(reply) => return Ok(reply.encode()),
+                    Err(err) if is_retryable(&err) => last_err = err,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            Err(exhausted(policy, last_err))
+        }
+    }
+}
+
+/// Whether a failed attempt at a transaction is worth repeating
+fn is_retryable(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Timeout
+            | Error::Checksum
+            | Error::InsufficientData
+            | Error::InvalidOpcode(_)
+    )
+}
+
+/// The error to report once every attempt at a transaction has failed
+fn exhausted(policy: RetryPolicy, last_err: Error) -> Error {
+    match last_err {
+        Error::Timeout => Error::NoReply(policy.attempts.max(1)),
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IrTower, Packet, RetryPolicy, TowerConfig};
+    use crate::{
+        opcodes,
+        tower::mock::{MockTower, Reply},
+        Error,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn retry_with_alternate_opcode() {
+        let mut tower = MockTower::new();
+        tower.push(Reply::Timeout);
+        tower.push(Reply::Payload(vec![0x43, 0x1e]));
+
+        let resp = tower.send_recv(&opcodes::GetBatteryPower {}).unwrap();
+        assert_eq!(
+            resp,
+            [
+                0x55, 0xff, 0x00, 0xc7, 0x38, 0x43, 0xbc, 0x1e, 0xe1, 0x28,
+                0xd7
+            ]
+        );
+
+        let sent = tower.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].frame[3], 0x30);
+        assert_eq!(sent[1].frame[3], 0x38);
+    }
+
+    #[test]
+    fn stale_reply_is_rejected() {
+        let mut tower = MockTower::new();
+        // reply to the alternate form, although the plain form was sent
+        tower.push_frame(Packet::new(0xc7, vec![0x43, 0x1e]).encode());
+        tower.push(Reply::Timeout);
+        tower.push(Reply::Timeout);
+
+        assert!(matches!(
+            tower.send_recv(&opcodes::GetBatteryPower {}),
+            Err(Error::NoReply(3))
+        ));
+    }
+
+    #[test]
+    fn configured_attempts() {
+        let mut tower = MockTower::new()
+            .with_responder(|_| Reply::Timeout)
+            .with_config(TowerConfig::new().retry_policy(RetryPolicy {
+                attempts: 5,
+                backoff: Duration::from_millis(1),
+            }));
+
+        assert!(matches!(
+            tower.send_recv(&opcodes::Alive {}),
+            Err(Error::NoReply(5))
+        ));
+        assert_eq!(tower.sent().len(), 5);
+    }
+
+    #[test]
+    fn checksum_failure_is_retried() {
+        let mut tower = MockTower::new();
+        let mut frame = Packet::new(0xcf, vec![0x43, 0x1e]).encode();
+        *frame.last_mut().unwrap() ^= 0x01;
+        tower.push_frame(frame.clone());
+        tower.push_frame(frame);
+        tower.push(Reply::Payload(vec![0x43, 0x1e]));
+
+        tower.send_recv(&opcodes::GetBatteryPower {}).unwrap();
+        assert_eq!(tower.sent().len(), 3);
+    }
+
+    #[test]
+    fn exhausted_retries_report_last_error() {
+        let mut tower = MockTower::new();
+        for _ in 0..3 {
+            tower.push_frame(vec![0x55, 0xff, 0x00, 0x12, 0x34]);
+        }
+
+        assert!(matches!(
+            tower.send_recv(&opcodes::Alive {}),
+            Err(Error::InsufficientData)
+        ));
+    }
+
+    #[test]
+    fn echo_is_ignored() {
+        let mut tower = MockTower::new();
+        let mut frame = Packet::new(0x10, Vec::new()).encode();
+        frame.extend(Packet::new(0xef, Vec::new()).encode());
+        tower.push_frame(frame);
+
+        tower.send_recv(&opcodes::Alive {}).unwrap();
+        assert_eq!(tower.sent().len(), 1);
+    }
+
+    #[test]
+    fn no_reply_expected() {
+        let mut tower = MockTower::new().with_responder(|_| Reply::Timeout);
+        assert!(tower
+            .send_recv(&opcodes::SetMessage { message: 1 })
+            .unwrap()
+            .is_empty());
     }
 }
@@ -0,0 +1,280 @@
+//! An in-memory [`IrTower`] for testing code that drives an RCX without
+//! any hardware attached.
+//!
+//! ```
+//! use rcx::{tower::mock::MockTower, Rcx};
+//!
+//! let tower = MockTower::new();
+//! tower.push_reply(vec![0x43, 0x1e]);
+//! let mut rcx = Rcx::new(tower.clone());
+//!
+//! let battery = rcx.get_battery_power().unwrap();
+//! assert_eq!(battery.millivolts, 7747);
+//! tower.assert_sent(&[&rcx::opcodes::GetBatteryPower {}]);
+//! ```
+
+use crate::{opcodes::Opcode, tower::TowerConfig, Error, IrTower, Result};
+use nqc::packet::Packet;
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// A message sent through a [`MockTower`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sent {
+    /// Opcode of the request, without the alternate bit
+    pub opcode: u8,
+    /// Serialised parameters of the request
+    pub payload: Vec<u8>,
+    /// The complete packet as it would have been transmitted
+    pub frame: Vec<u8>,
+    /// Human-readable form of the request
+    pub description: String,
+}
+
+impl Sent {
+    /// Whether this is the given request, ignoring the alternate bit
+    pub fn is(&self, msg: &dyn Opcode) -> bool {
+        Packet::request(msg, false).is_ok_and(|packet| {
+            self.opcode == packet.opcode && self.payload == packet.payload
+        })
+    }
+}
+
+/// A scripted reply
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// Reply with the given parameters. The reply is framed with the
+    /// complement of the opcode that was sent.
+    Payload(Vec<u8>),
+    /// Return these bytes from `recv` verbatim
+    Frame(Vec<u8>),
+    /// Do not reply at all
+    Timeout,
+}
+
+type Responder = Box<dyn FnMut(&Sent) -> Reply + Send>;
+
+#[derive(Default)]
+struct State {
+    sent: Vec<Sent>,
+    replies: VecDeque<Reply>,
+    responder: Option<Responder>,
+    use_alternate_opcode: bool,
+    last_opcode: Option<u8>,
+    config: TowerConfig,
+}
+
+/// Records sent messages and plays back scripted replies.
+///
+/// Clones share the same state, so a clone can be kept to inspect the
+/// tower after handing it over to [`crate::Rcx`] or, with the `tokio`
+/// feature, [`crate::AsyncRcx`]. Replies queued with
+/// [`Self::push_reply`] and friends are used first; once the queue is
+/// empty the responder set with [`Self::with_responder`] is consulted,
+/// and without a responder every request is acknowledged with an empty
+/// reply.
+#[derive(Clone, Default)]
+pub struct MockTower {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTower {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // a panicking responder must not hide the state from other tests
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Compute replies for requests that have no scripted reply queued
+    pub fn with_responder(
+        self,
+        responder: impl FnMut(&Sent) -> Reply + Send + 'static,
+    ) -> Self {
+        self.state().responder = Some(Box::new(responder));
+        self
+    }
+
+    /// Set the config reported to [`crate::Rcx`]. Timings are ignored,
+    /// as the mock never waits.
+    pub fn with_config(self, config: TowerConfig) -> Self {
+        self.state().config = config;
+        self
+    }
+
+    /// Queue a reply carrying the given parameters
+    pub fn push_reply(&self, payload: Vec<u8>) {
+        self.push(Reply::Payload(payload));
+    }
+
+    /// Queue raw bytes to be returned from `recv`
+    pub fn push_frame(&self, frame: Vec<u8>) {
+        self.push(Reply::Frame(frame));
+    }
+
+    /// Queue a scripted reply
+    pub fn push(&self, reply: Reply) {
+        self.state().replies.push_back(reply);
+    }
+
+    /// All messages sent so far
+    pub fn sent(&self) -> Vec<Sent> {
+        self.state().sent.clone()
+    }
+
+    /// Forget all messages sent so far
+    pub fn clear(&self) {
+        self.state().sent.clear();
+    }
+
+    /// Panic unless exactly the given messages were sent, in order
+    #[track_caller]
+    pub fn assert_sent(&self, expected: &[&dyn Opcode]) {
+        let sent = self.sent();
+        let matches = sent.len() == expected.len()
+            && sent.iter().zip(expected).all(|(sent, msg)| sent.is(*msg));
+        assert!(
+            matches,
+            "sent messages differ\n  sent: {:?}\nexpected: {:?}",
+            sent.iter().map(|s| &s.description).collect::<Vec<_>>(),
+            expected
+                .iter()
+                .map(|msg| msg.to_string())
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    /// Panic unless the most recently sent message was `expected`
+    #[track_caller]
+    pub fn assert_last_sent(&self, expected: &dyn Opcode) {
+        let sent = self.sent();
+        let last = sent.last().expect("no messages were sent");
+        assert!(
+            last.is(expected),
+            "last sent message differs\n  sent: {}\nexpected: {expected}",
+            last.description,
+        );
+    }
+}
+
+impl IrTower for MockTower {
+    fn config(&self) -> TowerConfig {
+        self.state().config
+    }
+
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8> {
+        let mut state = self.state();
+        let packet = Packet::request(msg, state.use_alternate_opcode)?;
+        state.use_alternate_opcode = !state.use_alternate_opcode;
+        state.last_opcode = Some(packet.opcode);
+        let frame = packet.encode_framed(state.config.framing);
+        state.sent.push(Sent {
+            opcode: msg.request_opcode(),
+            payload: packet.payload.clone(),
+            frame,
+            description: msg.to_string(),
+        });
+        Ok(packet.opcode)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut state = self.state();
+        let reply = if let Some(reply) = state.replies.pop_front() {
+            reply
+        } else {
+            let state = &mut *state;
+            match (&mut state.responder, state.sent.last()) {
+                (Some(responder), Some(sent)) => responder(sent),
+                _ => Reply::Payload(Vec::new()),
+            }
+        };
+
+        match reply {
+            Reply::Payload(payload) => {
+                let opcode = state.last_opcode.ok_or(Error::Timeout)?;
+                Ok(Packet::new(!opcode, payload)
+                    .encode_framed(state.config.framing))
+            }
+            Reply::Frame(frame) => Ok(frame),
+            Reply::Timeout => Err(Error::Timeout),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl crate::tower::AsyncIrTower for MockTower {
+    fn config(&self) -> TowerConfig {
+        IrTower::config(self)
+    }
+
+    async fn send(&mut self, msg: &(dyn Opcode + Sync)) -> Result<u8> {
+        IrTower::send(self, msg)
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        IrTower::recv(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{opcodes, Sound};
+
+    #[test]
+    fn alternate_opcode() {
+        let mut tower = MockTower::new();
+        tower.send_recv(&opcodes::Alive {}).unwrap();
+        let resp = tower.send_recv(&opcodes::Alive {}).unwrap();
+
+        let sent = tower.sent();
+        assert_eq!(sent[0].frame, [0x55, 0xff, 0x00, 0x10, 0xef, 0x10, 0xef]);
+        assert_eq!(sent[1].frame, [0x55, 0xff, 0x00, 0x18, 0xe7, 0x18, 0xe7]);
+        assert_eq!(resp, [0x55, 0xff, 0x00, 0xe7, 0x18, 0xe7, 0x18]);
+        tower.assert_sent(&[&opcodes::Alive {}, &opcodes::Alive {}]);
+    }
+
+    #[test]
+    fn scripted_then_responder() {
+        let mut tower = MockTower::new().with_responder(|sent| {
+            if sent.opcode == 0x10 {
+                Reply::Timeout
+            } else {
+                Reply::Payload(vec![1])
+            }
+        });
+        tower.push_frame(vec![1, 2, 3]);
+
+        tower.send(&opcodes::Alive {}).unwrap();
+        assert_eq!(tower.recv().unwrap(), [1, 2, 3]);
+        tower.send(&opcodes::Alive {}).unwrap();
+        assert!(matches!(tower.recv(), Err(Error::Timeout)));
+
+        let resp = tower
+            .send_recv(&opcodes::SetDatalogSize { size: 10 })
+            .unwrap();
+        assert_eq!(
+            opcodes::SetDatalogSizeResponse::deserialise(&resp).unwrap(),
+            opcodes::SetDatalogSizeResponse { errorcode: 1 }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "sent messages differ")]
+    fn assert_sent_mismatch() {
+        let mut tower = MockTower::new();
+        tower
+            .send(&opcodes::PlaySound {
+                sound: Sound::BeepBeep,
+            })
+            .unwrap();
+        tower.assert_sent(&[&opcodes::PlaySound {
+            sound: Sound::DownwardTones,
+        }]);
+    }
+}
@@ -0,0 +1,388 @@
+//! Record the traffic of a real tower and replay it later, to reproduce
+//! problems seen with real hardware in a regression test.
+//!
+//! [`RecordingTower`] wraps another tower and logs every frame it sends
+//! and receives. [`ReplayTower`] reads such a log back, checks that the
+//! same requests are sent again and answers them with the recorded
+//! replies.
+//!
+//! The log is a text file with one event per line, prefixed with the
+//! number of seconds since recording began. Lines starting with `#` are
+//! comments.
+//! ```text
+//! 0.000 tx 55ff0030cf30cf
+//! 5.003 rx timeout
+//! 5.004 tx 55ff0038c738c7
+//! 5.131 rx 55ff00c73843bc1ee128d7
+//! ```
+
+use crate::{opcodes::Opcode, tower::TowerConfig, Error, IrTower, Result};
+use nqc::packet::Packet;
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Event {
+    /// The frame of a request, with its opcode as transmitted. It is
+    /// only decoded on replay, once the framing in use is known.
+    Tx(Vec<u8>),
+    /// Bytes returned from `recv`
+    Rx(Vec<u8>),
+    /// `recv` timed out
+    Timeout,
+    /// `recv` failed for another reason
+    Error(String),
+}
+
+impl Event {
+    fn parse(line: &str) -> Result<Self> {
+        let mut fields = line.splitn(3, ' ');
+        let (Some(time), Some(direction)) = (fields.next(), fields.next())
+        else {
+            return Err(Error::Parse("Truncated recording event"));
+        };
+        time.parse::<f64>()
+            .map_err(|_| Error::Parse("Invalid recording timestamp"))?;
+        let rest = fields.next().unwrap_or_default();
+
+        let decode = |data: &str| {
+            hex::decode(data)
+                .map_err(|_| Error::Parse("Invalid hex in recording"))
+        };
+        Ok(match (direction, rest) {
+            ("tx", "") => return Err(Error::Parse("Empty recorded frame")),
+            ("tx", frame) => Self::Tx(decode(frame)?),
+            ("rx", "timeout") => Self::Timeout,
+            ("rx", rest) => match rest.strip_prefix("error ") {
+                Some(message) => Self::Error(message.to_string()),
+                None => Self::Rx(decode(rest)?),
+            },
+            _ => return Err(Error::Parse("Unknown recording event")),
+        })
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Tx(frame) => write!(fmt, "tx {}", hex::encode(frame)),
+            Self::Rx(buf) => write!(fmt, "rx {}", hex::encode(buf)),
+            Self::Timeout => write!(fmt, "rx timeout"),
+            Self::Error(message) => {
+                write!(fmt, "rx error {}", message.replace('\n', " "))
+            }
+        }
+    }
+}
+
+/// Wraps a tower and logs all of its traffic.
+///
+/// Every event is flushed as soon as it happens, so the log is complete
+/// even if the program crashes.
+pub struct RecordingTower<T> {
+    inner: T,
+    out: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+impl<T: IrTower> RecordingTower<T> {
+    /// Record the traffic of `inner` to a new file at `path`
+    pub fn create(inner: T, path: impl AsRef<Path>) -> Result<Self> {
+        let out = BufWriter::new(File::create(path)?);
+        Ok(Self::new(inner, out))
+    }
+
+    /// Record the traffic of `inner` to `out`
+    pub fn new(inner: T, out: impl Write + Send + 'static) -> Self {
+        Self {
+            inner,
+            out: Box::new(out),
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, event: &Event) -> Result<()> {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        writeln!(self.out, "{elapsed:.3} {event}")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl<T: IrTower> IrTower for RecordingTower<T> {
+    fn config(&self) -> TowerConfig {
+        self.inner.config()
+    }
+
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8> {
+        let opcode = self.inner.send(msg)?;
+        let payload = Packet::request(msg, false)?.payload;
+        let frame = Packet::new(opcode, payload)
+            .encode_framed(self.inner.config().framing);
+        self.record(&Event::Tx(frame))?;
+        Ok(opcode)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        let result = self.inner.recv();
+        self.record(&match &result {
+            Ok(buf) => Event::Rx(buf.clone()),
+            Err(Error::Timeout) => Event::Timeout,
+            Err(err) => Event::Error(err.to_string()),
+        })?;
+        result
+    }
+}
+
+struct State {
+    /// Events with the line they were read from
+    events: Vec<(usize, Event)>,
+    position: usize,
+    config: TowerConfig,
+}
+
+impl State {
+    #[track_caller]
+    fn next(&mut self, action: &str) -> (usize, Event) {
+        let event = self.events.get(self.position).cloned();
+        let Some(event) = event else {
+            panic!("{action} after the end of the recording");
+        };
+        self.position += 1;
+        event
+    }
+}
+
+/// Plays back a log written by [`RecordingTower`].
+///
+/// Timestamps are ignored, so a replay runs as fast as the code driving
+/// it. Retries and framing are part of the recording, so the tower must
+/// be given the retry policy and framing in use when it was made with
+/// [`Self::with_config`].
+/// Recorded errors other than timeouts are returned as [`Error::Io`]
+/// with the original message.
+///
+/// Clones share the same state, so a clone can be kept to check
+/// [`Self::assert_finished`] after handing the tower over to
+/// [`crate::Rcx`].
+///
+/// # Panics
+/// `send` and `recv` panic if they are called in a different order to
+/// the recording, or if a request differs from the recorded one.
+#[derive(Clone)]
+pub struct ReplayTower {
+    state: Arc<Mutex<State>>,
+}
+
+impl ReplayTower {
+    /// Read a recording from a file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse the text of a recording
+    pub fn parse(recording: &str) -> Result<Self> {
+        let events = recording
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line_number, line)| Ok((line_number, Event::parse(line)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(State {
+                events,
+                position: 0,
+                config: TowerConfig::default(),
+            })),
+        })
+    }
+
+    /// Set the config reported to [`crate::Rcx`]
+    pub fn with_config(self, config: TowerConfig) -> Self {
+        self.state().config = config;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Panic unless every recorded event has been replayed
+    #[track_caller]
+    pub fn assert_finished(&self) {
+        let state = self.state();
+        if let Some((line, event)) = state.events.get(state.position) {
+            panic!("recording not finished, line {line} not replayed: {event}");
+        }
+    }
+}
+
+impl IrTower for ReplayTower {
+    fn config(&self) -> TowerConfig {
+        self.state().config
+    }
+
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8> {
+        let framing = self.config().framing;
+        let (line, event) = self.state().next(&format!("sent {msg}"));
+        let Event::Tx(frame) = event else {
+            panic!("line {line}: expected {event}, but sent {msg}");
+        };
+        let Ok(recorded) = Packet::decode_framed(&frame, framing) else {
+            panic!("line {line}: recorded frame is not in {framing:?} framing");
+        };
+
+        let plain = Packet::request(msg, false)?;
+        let alternate = Packet::request(msg, true)?;
+        if recorded.payload != plain.payload
+            || (recorded.opcode != plain.opcode
+                && recorded.opcode != alternate.opcode)
+        {
+            panic!(
+                "line {line}: sent {msg} ({}), but recorded {}",
+                hex::encode(plain.encode_framed(framing)),
+                hex::encode(frame),
+            );
+        }
+        Ok(recorded.opcode)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        let (line, event) = self.state().next("received");
+        match event {
+            Event::Rx(buf) => Ok(buf),
+            Event::Timeout => Err(Error::Timeout),
+            Event::Error(message) => Err(std::io::Error::other(message).into()),
+            Event::Tx(_) => {
+                panic!("line {line}: expected {event}, but received instead")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        opcodes,
+        tower::{
+            mock::{MockTower, Reply},
+            RetryPolicy,
+        },
+        Rcx,
+    };
+    use nqc::packet::Framing;
+    use std::time::Duration;
+
+    /// Log output which can be inspected after handing it to the tower
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const RECORDING: &str = "\
+# battery check, first reply lost
+0.000 tx 55ff0030cf30cf
+0.001 rx timeout
+0.002 tx 55ff0038c738c7
+0.003 rx 55ff00c73843bc1ee128d7
+";
+
+    fn config() -> TowerConfig {
+        TowerConfig::new().retry_policy(RetryPolicy {
+            attempts: 2,
+            backoff: Duration::ZERO,
+        })
+    }
+
+    #[test]
+    fn record() {
+        let mock = MockTower::new().with_config(config());
+        mock.push(Reply::Timeout);
+        mock.push_reply(vec![0x43, 0x1e]);
+        let log = SharedBuf::default();
+
+        let mut rcx = Rcx::new(RecordingTower::new(mock, log.clone()));
+        assert_eq!(rcx.get_battery_power().unwrap().millivolts, 7747);
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let events = log
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect::<Vec<_>>();
+        let expected = RECORDING
+            .lines()
+            .skip(1)
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn record_cybermaster() {
+        let config = TowerConfig::new().framing(Framing::CyberMaster);
+        let log = SharedBuf::default();
+        let mut tower = RecordingTower::new(
+            MockTower::new().with_config(config),
+            log.clone(),
+        );
+        tower.send(&opcodes::Alive {}).unwrap();
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(log.ends_with(" tx fe0000ff10ef10ef\n"), "{log}");
+
+        let mut replay = ReplayTower::parse(&log).unwrap().with_config(config);
+        replay.send(&opcodes::Alive {}).unwrap();
+        replay.assert_finished();
+    }
+
+    #[test]
+    fn replay() {
+        let tower =
+            ReplayTower::parse(RECORDING).unwrap().with_config(config());
+        let mut rcx = Rcx::new(tower.clone());
+        assert_eq!(rcx.get_battery_power().unwrap().millivolts, 7747);
+        tower.assert_finished();
+    }
+
+    #[test]
+    #[should_panic(expected = "line 2: sent Alive")]
+    fn divergent_request() {
+        let mut tower = ReplayTower::parse(RECORDING).unwrap();
+        let _ = tower.send_recv(&opcodes::Alive {});
+    }
+
+    #[test]
+    #[should_panic(expected = "sent GetBatteryPower after the end")]
+    fn past_the_end() {
+        let mut tower = ReplayTower::parse(RECORDING).unwrap();
+        tower.send(&opcodes::GetBatteryPower {}).unwrap();
+        tower.recv().unwrap_err();
+        tower.send(&opcodes::GetBatteryPower {}).unwrap();
+        tower.recv().unwrap();
+        tower.send(&opcodes::GetBatteryPower {}).unwrap();
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(ReplayTower::parse("0.000 tx").is_err());
+        assert!(ReplayTower::parse("0.000 rx zz").is_err());
+        assert!(ReplayTower::parse("0.000 sideways 00").is_err());
+        assert!(ReplayTower::parse("soon tx 55ff0010ef10ef").is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{
+    opcodes::Opcode,
+    tower::{wait_readable, TowerConfig},
+    Error, IrTower, Result,
+};
+use nix::sys::termios::{self, ControlFlags, SetArg, SpecialCharacterIndices};
+use nqc::packet::Packet;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    time::Instant,
+};
+use tracing::trace;
+
+/// Time to wait for further bytes before treating a reply as complete,
+/// in tenths of a second
+const INTER_BYTE_TIMEOUT_DS: u8 = 1;
+
+/// The original RS-232 IR tower, e.g. on `/dev/ttyS0` or a USB serial
+/// adapter. Communicates at 2400 baud with odd parity.
+///
+/// The tower receives its own transmissions, so every message sent is
+/// echoed back ahead of the reply and is stripped by [`Self::recv`],
+/// unless disabled with [`TowerConfig::strip_echo`].
+pub struct SerialTower {
+    device: File,
+    use_alternate_opcode: bool,
+    last_tx: Instant,
+    config: TowerConfig,
+    echo: Vec<u8>,
+}
+
+impl SerialTower {
+    pub fn open(device: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_config(device, TowerConfig::default())
+    }
+
+    pub fn open_with_config(
+        device: impl AsRef<Path>,
+        config: TowerConfig,
+    ) -> Result<Self> {
+        let device = OpenOptions::new().read(true).write(true).open(device)?;
+        configure(&device)?;
+        Ok(Self {
+            device,
+            use_alternate_opcode: false,
+            last_tx: Instant::now(),
+            config,
+            echo: Vec::new(),
+        })
+    }
+}
+
+/// Put the tty into raw mode at 2400 baud, 8 data bits, odd parity and
+/// one stop bit (8O1). Reads return after `INTER_BYTE_TIMEOUT_DS` of
+/// silence so that a whole reply can be collected.
+fn configure(device: &File) -> Result<()> {
+    let mut tio = termios::tcgetattr(device).map_err(std::io::Error::from)?;
+    termios::cfmakeraw(&mut tio);
+    termios::cfsetspeed(&mut tio, termios::BaudRate::B2400)
+        .map_err(std::io::Error::from)?;
+    tio.control_flags |= ControlFlags::CS8
+        | ControlFlags::PARENB
+        | ControlFlags::PARODD
+        | ControlFlags::CREAD
+        | ControlFlags::CLOCAL;
+    tio.control_flags &= !ControlFlags::CSTOPB;
+    tio.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+    tio.control_chars[SpecialCharacterIndices::VTIME as usize] =
+        INTER_BYTE_TIMEOUT_DS;
+    termios::tcsetattr(device, SetArg::TCSANOW, &tio)
+        .map_err(std::io::Error::from)?;
+    termios::tcflush(device, termios::FlushArg::TCIOFLUSH)
+        .map_err(std::io::Error::from)?;
+    Ok(())
+}
+
+/// Remove the echo of the last transmission from the start of `buf`.
+/// Returns `None` if `buf` so far only contains (part of) the echo.
+fn strip_echo<'a>(buf: &'a [u8], echo: &[u8]) -> Option<&'a [u8]> {
+    if let Some(reply) = buf.strip_prefix(echo) {
+        (!reply.is_empty()).then_some(reply)
+    } else if echo.starts_with(buf) {
+        None
+    } else {
+        Some(buf)
+    }
+}
+
+impl IrTower for SerialTower {
+    fn config(&self) -> TowerConfig {
+        self.config
+    }
+
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8> {
+        let packet = Packet::request(msg, self.use_alternate_opcode)?;
+        let buf = packet.encode_framed(self.config.framing);
+        self.use_alternate_opcode = !self.use_alternate_opcode;
+
+        trace!("send: {buf:02x?}");
+
+        // Enforce a minimum time separation between transmissions to
+        // avoid confusing the RCX
+        if let Some(wait) =
+            self.config.tx_gap.checked_sub(self.last_tx.elapsed())
+        {
+            std::thread::sleep(wait);
+        }
+
+        self.device.write_all(&buf)?;
+        self.device.flush()?;
+        self.last_tx = Instant::now();
+        if self.config.strip_echo {
+            self.echo = buf;
+        }
+        Ok(packet.opcode)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let deadline = Instant::now() + self.config.read_timeout;
+        loop {
+            // sleep until the first byte arrives; after that, reads
+            // return once the line has been quiet for a moment
+            if buf.is_empty() && !wait_readable(&self.device, deadline)? {
+                break;
+            }
+            let mut chunk = [0; 256];
+            let len = self.device.read(&mut chunk)?;
+            if len > 0 {
+                buf.extend_from_slice(&chunk[..len]);
+                continue;
+            }
+
+            // the line has gone quiet, so check if a reply has arrived
+            if let Some(reply) = strip_echo(&buf, &self.echo) {
+                trace!("recv: {reply:02x?}");
+                let reply = reply.to_vec();
+                self.echo.clear();
+                return Ok(reply);
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        Err(Error::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opcodes::{self, GetBatteryPowerResponse};
+    use nix::pty::openpty;
+
+    #[test]
+    fn echo_is_stripped() {
+        let pty = openpty(None, None).unwrap();
+        let path = nix::unistd::ttyname(&pty.slave).unwrap();
+        let mut tower = SerialTower::open(path).unwrap();
+        let mut brick = File::from(pty.master);
+
+        let request = [0x55, 0xff, 0x00, 0x30, 0xcf, 0x30, 0xcf];
+        let reply = [
+            0x55, 0xff, 0x00, 0xcf, 0x30, 0x43, 0xbc, 0x1e, 0xe1, 0x30, 0xcf,
+        ];
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 7];
+            brick.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, request);
+            // the tower hears its own transmission before the reply
+            brick.write_all(&buf).unwrap();
+            brick.write_all(&reply).unwrap();
+            brick
+        });
+
+        let resp = tower.send_recv(&opcodes::GetBatteryPower {}).unwrap();
+        assert_eq!(resp, reply);
+        assert_eq!(
+            GetBatteryPowerResponse::deserialise(&resp).unwrap(),
+            GetBatteryPowerResponse { millivolts: 7747 }
+        );
+        drop(handle.join().unwrap());
+    }
+
+    #[test]
+    fn short_timeout() {
+        let pty = openpty(None, None).unwrap();
+        let path = nix::unistd::ttyname(&pty.slave).unwrap();
+        let config = TowerConfig::new()
+            .read_timeout(std::time::Duration::from_millis(50))
+            .attempts(2);
+        let mut tower = SerialTower::open_with_config(path, config).unwrap();
+
+        let start = Instant::now();
+        assert!(matches!(
+            tower.send_recv(&opcodes::Alive {}),
+            Err(Error::NoReply(2))
+        ));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        drop(pty.master);
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(strip_echo(&[1, 2], &[1, 2, 3]), None);
+        assert_eq!(strip_echo(&[1, 2, 3], &[1, 2, 3]), None);
+        assert_eq!(strip_echo(&[1, 2, 3, 4], &[1, 2, 3]), Some(&[4][..]));
+        assert_eq!(strip_echo(&[4, 5], &[1, 2, 3]), Some(&[4, 5][..]));
+        assert_eq!(strip_echo(&[4, 5], &[]), Some(&[4, 5][..]));
+    }
+}
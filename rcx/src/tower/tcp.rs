@@ -0,0 +1,402 @@
+//! Share a tower over TCP, so that a tower plugged into one machine can
+//! be used from another.
+//!
+//! [`serve`] exposes any [`IrTower`] to clients, which connect with
+//! [`TcpTower`]. The client forwards individual `send` and `recv` calls
+//! and the server performs them on its tower, so retries are driven by
+//! the client while the timing of the IR link is handled by the server.
+//!
+//! Every message is a frame of a big-endian u16 length followed by
+//! that many bytes:
+//! ```text
+//! Client to server
+//! * 0x01 send - opcode, flags, response opcode, payload
+//!   flags: 0x01 - a reply is expected, 0x02 - supports alternate
+//! * 0x02 recv
+//! * 0x03 framing - sent once on connecting
+//!
+//! Server to client
+//! * 0x00 ok - transmitted opcode for send, received bytes for recv,
+//!   the framing of the server's tower for framing: 0x00 RCX,
+//!   0x01 CyberMaster, 0x02 Spybotics, 0x03 raw
+//! * 0x01 error - kind, followed by its argument or message
+//! ```
+
+use crate::{opcodes::Opcode, tower::TowerConfig, Error, IrTower, Result};
+use nqc::packet::{Framing, Packet};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+use tracing::{debug, warn};
+
+const SEND: u8 = 0x01;
+const RECV: u8 = 0x02;
+const FRAMING: u8 = 0x03;
+
+const OK: u8 = 0x00;
+const ERR: u8 = 0x01;
+
+const HAS_RESPONSE: u8 = 0x01;
+const SUPPORTS_ALTERNATE: u8 = 0x02;
+
+// Error kinds which the client's retry logic needs to distinguish.
+// Anything else is passed on as a message.
+const ERR_OTHER: u8 = 0x00;
+const ERR_TIMEOUT: u8 = 0x01;
+const ERR_NO_REPLY: u8 = 0x02;
+const ERR_CHECKSUM: u8 = 0x03;
+const ERR_INSUFFICIENT_DATA: u8 = 0x04;
+const ERR_INVALID_OPCODE: u8 = 0x05;
+
+/// Framings indexed by their number in the protocol
+const FRAMINGS: [Framing; 4] = [
+    Framing::Rcx,
+    Framing::CyberMaster,
+    Framing::Spybotics,
+    Framing::Raw,
+];
+
+fn write_frame(stream: &mut impl Write, body: &[u8]) -> Result<()> {
+    let len = u16::try_from(body.len())?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut body = vec![0; u16::from_be_bytes(len).into()];
+    stream.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn encode_error(err: &Error) -> Vec<u8> {
+    match err {
+        Error::Timeout => vec![ERR_TIMEOUT],
+        Error::NoReply(attempts) => {
+            vec![ERR_NO_REPLY, (*attempts).min(u8::MAX.into()) as u8]
+        }
+        Error::Checksum => vec![ERR_CHECKSUM],
+        Error::InsufficientData => vec![ERR_INSUFFICIENT_DATA],
+        Error::InvalidOpcode(opcode) => vec![ERR_INVALID_OPCODE, *opcode],
+        err => {
+            let mut buf = vec![ERR_OTHER];
+            buf.extend_from_slice(err.to_string().as_bytes());
+            buf
+        }
+    }
+}
+
+fn decode_error(buf: &[u8]) -> Error {
+    match buf {
+        [ERR_TIMEOUT] => Error::Timeout,
+        [ERR_NO_REPLY, attempts] => Error::NoReply((*attempts).into()),
+        [ERR_CHECKSUM] => Error::Checksum,
+        [ERR_INSUFFICIENT_DATA] => Error::InsufficientData,
+        [ERR_INVALID_OPCODE, opcode] => Error::InvalidOpcode(*opcode),
+        [ERR_OTHER, message @ ..] => {
+            Error::Remote(String::from_utf8_lossy(message).into_owned())
+        }
+        _ => Error::InvalidData("Malformed error from tower server"),
+    }
+}
+
+/// Client for a tower shared with [`serve`].
+///
+/// The server's tower applies its own timeouts, pacing and echo
+/// handling, so only the retry policy and range of the config are used.
+/// Replies are framed by the server's tower, so its framing replaces
+/// the one in the config when connecting.
+pub struct TcpTower {
+    stream: TcpStream,
+    config: TowerConfig,
+}
+
+impl TcpTower {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::connect_with_config(addr, TowerConfig::default())
+    }
+
+    pub fn connect_with_config(
+        addr: impl ToSocketAddrs,
+        config: TowerConfig,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut tower = Self { stream, config };
+        let framing = match tower.transact(&[FRAMING])?.as_slice() {
+            &[framing] => FRAMINGS.get(usize::from(framing)).copied(),
+            _ => None,
+        };
+        tower.config.framing = framing
+            .ok_or(Error::InvalidData("Unknown framing from tower server"))?;
+        Ok(tower)
+    }
+
+    fn transact(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        write_frame(&mut self.stream, request)?;
+        let reply = read_frame(&mut self.stream)?;
+        match reply.split_first() {
+            Some((&OK, data)) => Ok(data.to_vec()),
+            Some((&ERR, err)) => Err(decode_error(err)),
+            _ => Err(Error::InvalidData("Malformed reply from tower server")),
+        }
+    }
+}
+
+impl IrTower for TcpTower {
+    fn config(&self) -> TowerConfig {
+        self.config
+    }
+
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8> {
+        // the server's tower chooses the form of the opcode
+        let packet = Packet::request(msg, false)?;
+        let mut flags = 0;
+        if msg.response_opcode().is_some() {
+            flags |= HAS_RESPONSE;
+        }
+        if msg.supports_alternate() {
+            flags |= SUPPORTS_ALTERNATE;
+        }
+
+        let mut request = vec![
+            SEND,
+            packet.opcode,
+            flags,
+            msg.response_opcode().unwrap_or(0),
+        ];
+        request.extend_from_slice(&packet.payload);
+        match self.transact(&request)?.as_slice() {
+            [opcode] => Ok(*opcode),
+            _ => Err(Error::InvalidData("Malformed reply from tower server")),
+        }
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        self.transact(&[RECV])
+    }
+}
+
+/// A request received from a client, to be sent by the server's tower
+#[derive(Debug)]
+struct Forwarded {
+    opcode: u8,
+    response: Option<u8>,
+    supports_alternate: bool,
+    payload: Vec<u8>,
+}
+
+impl Display for Forwarded {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{:02x} {}", self.opcode, hex::encode(&self.payload))
+    }
+}
+
+impl Opcode for Forwarded {
+    /// The reply is returned to the client as it is
+    type Response = Vec<u8>;
+
+    fn request_opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    fn response_opcode(&self) -> Option<u8> {
+        self.response
+    }
+
+    fn parse_response(reply: &[u8]) -> Result<Self::Response> {
+        Ok(reply.to_vec())
+    }
+
+    fn supports_alternate(&self) -> bool {
+        self.supports_alternate
+    }
+
+    fn serialise(&self, buf: &mut [u8]) -> Result<usize> {
+        buf.get_mut(..self.payload.len())
+            .ok_or(Error::InsufficientData)?
+            .copy_from_slice(&self.payload);
+        Ok(self.payload.len())
+    }
+
+    fn disasm(_bin: &[u8], _pc: &mut usize) -> Result<Self> {
+        Err(Error::InvalidData(
+            "Forwarded requests cannot be disassembled",
+        ))
+    }
+}
+
+/// Serve `tower` to clients connecting to `listener`. Clients are
+/// handled one at a time, as they would otherwise confuse each other's
+/// transactions with the brick.
+///
+/// Clients are not authenticated, and every request is forwarded.
+pub fn serve(listener: TcpListener, tower: &mut dyn IrTower) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        debug!("Client {peer} connected");
+        match serve_client(stream, tower) {
+            Ok(()) => debug!("Client {peer} disconnected"),
+            Err(err) => warn!("Client {peer} failed: {err}"),
+        }
+    }
+    Ok(())
+}
+
+/// Handle requests from a single client until it disconnects
+pub fn serve_client(
+    mut stream: TcpStream,
+    tower: &mut dyn IrTower,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    loop {
+        let request = match read_frame(&mut stream) {
+            Ok(request) => request,
+            Err(Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        let result = match request.as_slice() {
+            [SEND, opcode, flags, response, payload @ ..] => {
+                let msg = Forwarded {
+                    opcode: *opcode,
+                    response: (flags & HAS_RESPONSE != 0).then_some(*response),
+                    supports_alternate: flags & SUPPORTS_ALTERNATE != 0,
+                    payload: payload.to_vec(),
+                };
+                tower.send(&msg).map(|opcode| vec![opcode])
+            }
+            [RECV] => tower.recv(),
+            [FRAMING] => {
+                let number = match tower.config().framing {
+                    Framing::Rcx => 0,
+                    Framing::CyberMaster => 1,
+                    Framing::Spybotics => 2,
+                    Framing::Raw => 3,
+                };
+                Ok(vec![number])
+            }
+            _ => {
+                return Err(Error::InvalidData(
+                    "Malformed request from tower client",
+                ));
+            }
+        };
+
+        let reply = match result {
+            Ok(data) => [&[OK], data.as_slice()].concat(),
+            Err(err) => [vec![ERR], encode_error(&err)].concat(),
+        };
+        write_frame(&mut stream, &reply)?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        opcodes,
+        tower::{
+            mock::{MockTower, Reply},
+            RetryPolicy,
+        },
+        Rcx,
+    };
+    use std::{thread::JoinHandle, time::Duration};
+
+    /// Serve `tower` to a single client on an ephemeral port
+    fn server(tower: MockTower) -> (String, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut tower = tower;
+            let (stream, _) = listener.accept().unwrap();
+            serve_client(stream, &mut tower).unwrap();
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn rcx_over_tcp() {
+        let tower = MockTower::new();
+        tower.push_reply(vec![0x43, 0x1e]);
+        let (addr, handle) = server(tower.clone());
+
+        let mut rcx = Rcx::new(TcpTower::connect(addr).unwrap());
+        assert_eq!(rcx.get_battery_power().unwrap().millivolts, 7747);
+        rcx.set_message(3).unwrap();
+        drop(rcx);
+        handle.join().unwrap();
+
+        tower.assert_sent(&[
+            &opcodes::GetBatteryPower {},
+            &opcodes::SetMessage { message: 3 },
+        ]);
+    }
+
+    #[test]
+    fn framing_from_server() {
+        let config = TowerConfig::new().framing(Framing::CyberMaster);
+        let tower = MockTower::new().with_config(config);
+        tower.push_reply(vec![0x43, 0x1e]);
+        let (addr, handle) = server(tower);
+
+        let client = TcpTower::connect(addr).unwrap();
+        assert_eq!(client.config().framing, Framing::CyberMaster);
+        let mut rcx = Rcx::new(client);
+        assert_eq!(rcx.get_battery_power().unwrap().millivolts, 7747);
+        drop(rcx);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn errors_are_forwarded() {
+        let tower = MockTower::new().with_responder(|_| Reply::Timeout);
+        tower.push_frame(Packet::new(0x99, Vec::new()).encode());
+        let (addr, handle) = server(tower.clone());
+
+        let config = TowerConfig::new().retry_policy(RetryPolicy {
+            attempts: 3,
+            backoff: Duration::ZERO,
+        });
+        let mut client = TcpTower::connect_with_config(addr, config).unwrap();
+        // a stray packet, then no reply at all
+        assert!(matches!(
+            client.send_recv(&opcodes::Alive {}),
+            Err(Error::NoReply(3))
+        ));
+        drop(client);
+        handle.join().unwrap();
+        assert_eq!(tower.sent().len(), 3);
+    }
+
+    #[test]
+    fn error_encoding() {
+        for err in [
+            Error::Timeout,
+            Error::NoReply(4),
+            Error::Checksum,
+            Error::InsufficientData,
+            Error::InvalidOpcode(0x12),
+            Error::NotChardev,
+        ] {
+            assert_eq!(
+                decode_error(&encode_error(&err)).to_string(),
+                match err {
+                    Error::NotChardev => {
+                        "Tower server error: Path is not a chardev".to_string()
+                    }
+                    err => err.to_string(),
+                }
+            );
+        }
+    }
+}
@@ -1,4 +1,13 @@
-use crate::{opcodes::Opcode, Error, IrTower, Result};
+#[cfg(feature = "tokio")]
+use crate::tower::AsyncIrTower;
+use crate::{
+    opcodes::Opcode,
+    tower::{wait_readable, TowerConfig},
+    Error, IrTower, Result,
+};
+use nqc::packet::Packet;
+#[cfg(feature = "tokio")]
+use std::os::unix::fs::OpenOptionsExt;
 use std::{
     fs::{File, OpenOptions},
     io::{Read, Write},
@@ -6,85 +15,300 @@ use std::{
     path::Path,
     time::{Duration, Instant},
 };
+#[cfg(feature = "tokio")]
+use tokio::io::unix::AsyncFd;
+use tracing::trace;
 
-const HEADER: [u8; 2] = [0x55, 0xff];
-const READ_TIMEOUT: Duration = Duration::from_secs(5);
-const TX_SEPARATION: Duration = Duration::from_millis(300);
+/// A reply is complete once the line has been quiet for this long
+const INTER_BYTE_TIMEOUT: Duration = Duration::from_millis(50);
 
 pub struct UsbTower {
     device: File,
     use_alternate_opcode: bool,
     last_tx: Instant,
+    config: TowerConfig,
 }
 
 impl UsbTower {
     pub fn open(device: impl AsRef<Path>) -> Result<Self> {
-        let device = device.as_ref();
-
-        let file_type = device.metadata()?.file_type();
-        if !file_type.is_char_device() {
-            return Err(Error::NotChardev);
-        }
+        Self::open_with_config(device, TowerConfig::default())
+    }
 
-        let device = OpenOptions::new().read(true).write(true).open(device)?;
+    pub fn open_with_config(
+        device: impl AsRef<Path>,
+        config: TowerConfig,
+    ) -> Result<Self> {
+        let device = open_device(device.as_ref(), OpenOptions::new())?;
         Ok(Self {
             device,
             use_alternate_opcode: false,
             last_tx: Instant::now(),
+            config,
         })
     }
 }
 
+/// Open the tower's character device for reading and writing
+fn open_device(device: &Path, mut options: OpenOptions) -> Result<File> {
+    let file_type = device.metadata()?.file_type();
+    if !file_type.is_char_device() {
+        return Err(Error::NotChardev);
+    }
+
+    Ok(options.read(true).write(true).open(device)?)
+}
+
 impl IrTower for UsbTower {
-    fn send(&mut self, msg: &dyn Opcode) -> Result<()> {
-        let mut opcode = msg.request_opcode();
-        if self.use_alternate_opcode {
-            opcode |= 0x08;
-        }
-        self.use_alternate_opcode = !self.use_alternate_opcode;
+    fn config(&self) -> TowerConfig {
+        self.config
+    }
 
-        let mut buf = [0; 1024];
-        let msg = msg.serialise(&mut buf)?;
-        let msg = &buf[..msg];
-        let mut buf = Vec::<u8>::new();
-        buf.extend_from_slice(&HEADER);
-        let mut sum = 0u8;
-
-        buf.push(opcode);
-        buf.push(!opcode);
-        sum += opcode;
-
-        for &byte in msg {
-            buf.push(byte);
-            buf.push(!byte);
-            sum = sum.wrapping_add(byte);
-        }
-        buf.push(sum);
-        buf.push(!sum);
+    fn send(&mut self, msg: &dyn Opcode) -> Result<u8> {
+        let packet = Packet::request(msg, self.use_alternate_opcode)?;
+        let buf = packet.encode_framed(self.config.framing);
+        self.use_alternate_opcode = !self.use_alternate_opcode;
 
-        println!("send: {buf:02x?}");
+        trace!("send: {buf:02x?}");
 
         // Enforce a minimum time separation between transmissions to
         // avoid confusing the RCX
-        while self.last_tx.elapsed() < TX_SEPARATION {
-            std::thread::sleep(Duration::from_millis(5));
+        if let Some(wait) =
+            self.config.tx_gap.checked_sub(self.last_tx.elapsed())
+        {
+            std::thread::sleep(wait);
         }
 
         self.device.write_all(&buf)?;
         self.device.flush()?;
         self.last_tx = Instant::now();
-        Ok(())
+        Ok(packet.opcode)
     }
 
     fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut deadline = Instant::now() + self.config.read_timeout;
+        while wait_readable(&self.device, deadline)? {
+            // the driver may time out a read and return nothing, in
+            // which case keep waiting until our own deadline
+            let mut chunk = [0; 256];
+            let len = self.device.read(&mut chunk)?;
+            if len > 0 {
+                buf.extend_from_slice(&chunk[..len]);
+                deadline = Instant::now() + INTER_BYTE_TIMEOUT;
+            }
+        }
+        if buf.is_empty() {
+            Err(Error::Timeout)
+        } else {
+            Ok(buf)
+        }
+    }
+}
+
+/// Async version of [`UsbTower`]. Waits for the device with tokio
+/// rather than blocking the thread, and paces transmissions with
+/// [`tokio::time::sleep`].
+#[cfg(feature = "tokio")]
+pub struct AsyncUsbTower {
+    device: AsyncFd<File>,
+    use_alternate_opcode: bool,
+    last_tx: tokio::time::Instant,
+    config: TowerConfig,
+    /// Bytes of a reply read by a `recv` which was cancelled before the
+    /// reply was complete
+    pending: Vec<u8>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncUsbTower {
+    /// Open the tower. Must be called from within a tokio runtime.
+    pub fn open(device: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_config(device, TowerConfig::default())
+    }
+
+    pub fn open_with_config(
+        device: impl AsRef<Path>,
+        config: TowerConfig,
+    ) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.custom_flags(nix::fcntl::OFlag::O_NONBLOCK.bits());
+        let device = open_device(device.as_ref(), options)?;
+        Ok(Self {
+            device: AsyncFd::new(device)?,
+            use_alternate_opcode: false,
+            last_tx: tokio::time::Instant::now(),
+            config,
+            pending: Vec::new(),
+        })
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncIrTower for AsyncUsbTower {
+    fn config(&self) -> TowerConfig {
+        self.config
+    }
+
+    async fn send(&mut self, msg: &(dyn Opcode + Sync)) -> Result<u8> {
+        let packet = Packet::request(msg, self.use_alternate_opcode)?;
+        let buf = packet.encode_framed(self.config.framing);
+        self.use_alternate_opcode = !self.use_alternate_opcode;
+
+        trace!("send: {buf:02x?}");
+
+        // Enforce a minimum time separation between transmissions to
+        // avoid confusing the RCX
+        tokio::time::sleep_until(self.last_tx + self.config.tx_gap).await;
+
+        let mut written = 0;
+        while written < buf.len() {
+            let mut guard = self.device.writable().await?;
+            if let Ok(len) =
+                guard.try_io(|device| device.get_ref().write(&buf[written..]))
+            {
+                written += len?;
+            }
+        }
+        self.last_tx = tokio::time::Instant::now();
+        Ok(packet.opcode)
+    }
+
+    /// Cancellation safe: bytes read before the future is dropped are
+    /// kept, and the next call carries on collecting the same reply
+    async fn recv(&mut self) -> Result<Vec<u8>> {
+        if self.pending.is_empty() {
+            self.pending = tokio::time::timeout(
+                self.config.read_timeout,
+                self.read_chunk(),
+            )
+            .await
+            .unwrap_or(Err(Error::Timeout))?;
+        }
+        while let Ok(chunk) =
+            tokio::time::timeout(INTER_BYTE_TIMEOUT, self.read_chunk()).await
+        {
+            self.pending.extend(chunk?);
+        }
+        Ok(std::mem::take(&mut self.pending))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncUsbTower {
+    /// Wait for and return whatever bytes are available. Cancellation
+    /// safe: bytes are only read from the device once they are
+    /// available, and are returned from the same poll.
+    async fn read_chunk(&mut self) -> Result<Vec<u8>> {
         let mut buf = vec![0; 256];
-        let now = Instant::now();
-        while now.elapsed() < READ_TIMEOUT {
-            if let Ok(len) = self.device.read(&mut buf) {
-                buf.truncate(len);
-                return Ok(buf);
+        loop {
+            let mut guard = self.device.readable().await?;
+            match guard.try_io(|device| device.get_ref().read(&mut buf)) {
+                // the device is non-blocking, so an empty read means it
+                // has gone away rather than that there is nothing to read
+                Ok(Ok(0)) => {
+                    return Err(std::io::Error::from(
+                        std::io::ErrorKind::UnexpectedEof,
+                    )
+                    .into())
+                }
+                Ok(Ok(len)) => {
+                    buf.truncate(len);
+                    return Ok(buf);
+                }
+                Ok(Err(err)) => return Err(err.into()),
+                Err(_would_block) => {}
             }
         }
-        Err(Error::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "tokio")]
+    use crate::opcodes;
+    use nix::{pty::openpty, sys::termios};
+
+    /// A pseudo-terminal in raw mode stands in for the tower's
+    /// character device
+    fn pty() -> (File, std::path::PathBuf) {
+        let pty = openpty(None, None).unwrap();
+        let mut tio = termios::tcgetattr(&pty.slave).unwrap();
+        termios::cfmakeraw(&mut tio);
+        termios::tcsetattr(&pty.slave, termios::SetArg::TCSANOW, &tio).unwrap();
+        let path = nix::unistd::ttyname(&pty.slave).unwrap();
+        // keep the slave open so that the master does not see a hangup
+        std::mem::forget(pty.slave);
+        (File::from(pty.master), path)
+    }
+
+    #[test]
+    fn recv_joins_reads() {
+        let (mut brick, path) = pty();
+        let mut tower = UsbTower::open(path).unwrap();
+
+        let handle = std::thread::spawn(move || {
+            brick.write_all(&[1, 2]).unwrap();
+            std::thread::sleep(INTER_BYTE_TIMEOUT / 5);
+            brick.write_all(&[3]).unwrap();
+            brick
+        });
+
+        assert_eq!(tower.recv().unwrap(), [1, 2, 3]);
+        drop(handle.join().unwrap());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn send_recv() {
+        let (mut brick, path) = pty();
+        let mut tower = AsyncUsbTower::open(path).unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 7];
+            brick.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [0x55, 0xff, 0x00, 0x30, 0xcf, 0x30, 0xcf]);
+            brick
+                .write_all(&Packet::new(0xcf, vec![0x43, 0x1e]).encode())
+                .unwrap();
+            brick
+        });
+
+        let resp = tower.send_recv(&opcodes::GetBatteryPower {}).await;
+        assert_eq!(
+            opcodes::GetBatteryPowerResponse::deserialise(&resp.unwrap())
+                .unwrap()
+                .millivolts,
+            7747
+        );
+        drop(handle.join().unwrap());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn timeout_is_cancellation_safe() {
+        let (mut brick, path) = pty();
+        let config = TowerConfig::new()
+            .read_timeout(Duration::from_millis(20))
+            .attempts(1);
+        let mut tower = AsyncUsbTower::open_with_config(path, config).unwrap();
+
+        assert!(matches!(
+            tower.send_recv(&opcodes::Alive {}).await,
+            Err(Error::NoReply(1))
+        ));
+
+        // a reply arriving after the timeout is not lost
+        brick.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(tower.recv().await.unwrap(), [1, 2, 3]);
+
+        // nor is the start of a reply whose read is cancelled while
+        // waiting for the rest
+        brick.write_all(&[4, 5]).unwrap();
+        assert!(tokio::time::timeout(INTER_BYTE_TIMEOUT / 2, tower.recv())
+            .await
+            .is_err());
+        brick.write_all(&[6]).unwrap();
+        assert_eq!(tower.recv().await.unwrap(), [4, 5, 6]);
     }
 }